@@ -3,20 +3,29 @@ use bincode::{
     DefaultOptions, Options,
 };
 
+/// The version of the ABI between the host and the plugins, embedded in the plugin metadata.
+/// The macros have their own copy of it, which must be kept in sync
+pub const ABI_VERSION: u32 = 1;
+
 lazy_static::lazy_static! {
     static ref BINCODE_OPTIONS: WithOtherLimit<DefaultOptions, Bounded> = DefaultOptions::new().with_limit(u32::MAX as u64);
 }
 
-fn from_bytes<T>(bytes: &[u8]) -> T
+pub fn from_bytes<T>(bytes: &[u8]) -> T
 where
     T: for<'a> serde::Deserialize<'a>,
 {
-    BINCODE_OPTIONS
-        .deserialize(bytes)
-        .expect("Unexpect error decoding bincode-encoded ABI message")
+    try_from_bytes(bytes).expect("Unexpect error decoding bincode-encoded ABI message")
+}
+
+pub(crate) fn try_from_bytes<T>(bytes: &[u8]) -> bincode::Result<T>
+where
+    T: for<'a> serde::Deserialize<'a>,
+{
+    BINCODE_OPTIONS.deserialize(bytes)
 }
 
-fn into_bytes<T>(v: &T) -> Vec<u8>
+pub fn into_bytes<T>(v: &T) -> Vec<u8>
 where
     T: serde::Serialize + ?Sized,
 {
@@ -25,8 +34,37 @@ where
         .expect("Error serialising bincode-encoded value")
 }
 
+/// The arguments of a call to another plugin, as a tuple. Each of them is encoded on its own, like the API functions take them
+pub trait CallArgs {
+	fn encode(&self) -> Vec<Vec<u8>>;
+}
+
+macro_rules! impl_call_args {
+	($($arg:ident),*) => {
+		impl<$($arg: serde::Serialize),*> CallArgs for ($($arg,)*) {
+			#[allow(non_snake_case)]
+			fn encode(&self) -> Vec<Vec<u8>> {
+				let ($($arg,)*) = self;
+				vec![$(into_bytes($arg)),*]
+			}
+		}
+	};
+}
+
+impl_call_args!();
+impl_call_args!(A);
+impl_call_args!(A, B);
+impl_call_args!(A, B, C);
+impl_call_args!(A, B, C, D);
+impl_call_args!(A, B, C, D, E);
+impl_call_args!(A, B, C, D, E, F);
+impl_call_args!(A, B, C, D, E, F, G);
+impl_call_args!(A, B, C, D, E, F, G, H);
+
 #[cfg(target_arch="wasm32")]
 pub mod wasm32 {
+	use super::CallArgs;
+
     pub fn from_abi<T>(ptr: u32) -> T
     where
         T: for<'a> serde::Deserialize<'a>,
@@ -62,6 +100,62 @@ pub mod wasm32 {
 		ptr
     }
 
+	#[link(wasm_import_module = "wasm_plugin_framework")]
+	extern "C" {
+		fn host_call(name_ptr: u32, name_len: u32, arg_ptr: u32) -> u32;
+		fn plugin_get(by_api: u32, name_ptr: u32, name_len: u32) -> i32;
+		fn plugin_call(handle: u32, function_ptr: u32, function_len: u32, args_ptr: u32) -> u32;
+	}
+
+	/// Calls the host function registered with this name in the host's `HostFunctions`.
+	/// The argument and the result go through the same ABI as the API functions.
+	pub fn call_host<A, R>(name: &str, arg: &A) -> R
+	where
+		A: serde::Serialize,
+		R: for<'a> serde::Deserialize<'a>,
+	{
+		let arg_ptr = into_abi(arg);
+		// The host frees the argument buffer, and allocates the result buffer, which from_abi frees
+		from_abi(unsafe { host_call(name.as_ptr() as u32, name.len() as u32, arg_ptr) })
+	}
+
+	/// A handle to another plugin this plugin depends on, whose API functions are called through the host.
+	/// Only the plugins and APIs declared with `requires(...)` can be obtained, and only when the plugin was loaded by a `PluginManager`
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct PluginHandle(u32);
+
+	impl PluginHandle {
+		/// The plugin with this name, if this plugin depends on it
+		pub fn by_name(name: &str) -> Option<Self> {
+			Self::get(false, name)
+		}
+
+		/// The plugin implementing this API, if this plugin depends on it
+		pub fn by_api(api_name: &str) -> Option<Self> {
+			Self::get(true, api_name)
+		}
+
+		fn get(by_api: bool, name: &str) -> Option<Self> {
+			let handle = unsafe { plugin_get(by_api as u32, name.as_ptr() as u32, name.len() as u32) };
+			if handle < 0 {
+				None
+			} else {
+				Some(Self(handle as u32))
+			}
+		}
+
+		/// Calls an API function of the plugin, with a tuple of its arguments.
+		/// When the handle was obtained by name, the function is looked up in all the APIs the plugin implements
+		pub fn call<A, R>(&self, function: &str, args: A) -> R
+		where
+			A: CallArgs,
+			R: for<'a> serde::Deserialize<'a>,
+		{
+			let args_ptr = into_abi(&args.encode());
+			from_abi(unsafe { plugin_call(self.0, function.as_ptr() as u32, function.len() as u32, args_ptr) })
+		}
+	}
+
 	#[no_mangle]
 	/// Allocates a full, empty vec, with the same capacity as the size provided (IT DOES NOT ADD 4 BYTES TO THE SIZE)
 	pub extern "C" fn allocate_buffer(size: u32) -> u32 {
@@ -90,7 +184,7 @@ pub use wasm32::*;
 #[cfg(not(target_arch="wasm32"))]
 pub mod not_wasm32 {
     use serde::{Deserialize, Serialize};
-    use wasmer::Memory;
+    use wasmer::{Function, Memory, RuntimeError, Val};
 
 	pub trait PluginLoader {
 		fn allocate_buffer(&self, size: u32) -> u32;
@@ -99,7 +193,11 @@ pub mod not_wasm32 {
 	}
 
 	pub fn into_abi<P, T>(plugin_loader: &P, t: &T) -> u32 where T: Serialize, P: PluginLoader {
-		let v = super::into_bytes(t);
+		into_abi_bytes(plugin_loader, &super::into_bytes(t))
+	}
+
+	/// Copies already encoded bytes into a new buffer in the plugin memory
+	pub fn into_abi_bytes<P>(plugin_loader: &P, v: &[u8]) -> u32 where P: PluginLoader {
 		let ptr = plugin_loader.allocate_buffer(v.len() as u32 + 4) as usize;
 		let m = plugin_loader.memory();
 		{
@@ -110,12 +208,17 @@ pub mod not_wasm32 {
 			//* including by calling a wasm function that reads the memory contents or by resizing this Memory.
 			let slice_mut = unsafe {m.data_unchecked_mut()};
 			slice_mut[ptr..ptr+4].copy_from_slice(&(v.len() as u32).to_le_bytes());
-			slice_mut[ptr+4..ptr+4+v.len()].copy_from_slice(&v);
+			slice_mut[ptr+4..ptr+4+v.len()].copy_from_slice(v);
 		};
 		ptr as u32
 	}
 
 	pub fn from_abi<P, T>(plugin_loader: &P, ptr: u32) -> T where T: for<'a> Deserialize<'a>, P: PluginLoader {
+		super::from_bytes(&from_abi_bytes(plugin_loader, ptr))
+	}
+
+	/// Reads (and frees) a buffer from the plugin memory, without decoding it
+	pub fn from_abi_bytes<P>(plugin_loader: &P, ptr: u32) -> Vec<u8> where P: PluginLoader {
 		let m = plugin_loader.memory();
 		let view = m.view();
 		let ptr = ptr as usize;
@@ -123,7 +226,29 @@ pub mod not_wasm32 {
 		let size = u32::from_le_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3]]);
 		let data = view[ptr+4..ptr+4+size as usize].iter().map(|x| x.get()).collect::<Vec<u8>>();
 		plugin_loader.free_buffer(ptr as u32, size + 4);
-		super::from_bytes(&data)
+		data
+	}
+
+	/// Copies `len` bytes at `ptr` out of the plugin memory, if they are all inside it.
+	/// Used for the pointers and lengths the plugins pass to the host, which can't be trusted
+	pub(crate) fn read_bytes(memory: &Memory, ptr: u32, len: u32) -> Option<Vec<u8>> {
+		let end = ptr.checked_add(len)?;
+		let view = memory.view::<u8>();
+		view.get(ptr as usize..end as usize).map(|bytes| bytes.iter().map(|x| x.get()).collect())
+	}
+
+	/// Calls an API function with already encoded arguments, copying each of them into the plugin memory, and returns the encoded result.
+	/// Functions without a result return nothing, which is encoded as an empty buffer like `()`
+	pub fn call_encoded<P>(plugin_loader: &P, function: &Function, args: &[Vec<u8>]) -> Result<Vec<u8>, RuntimeError> where P: PluginLoader {
+		let args: Vec<Val> = args
+			.iter()
+			.map(|arg| (into_abi_bytes(plugin_loader, arg) as i32).into())
+			.collect();
+		let results = function.call(&args)?;
+		Ok(match results.first() {
+			Some(ptr) => from_abi_bytes(plugin_loader, ptr.unwrap_i32() as u32),
+			None => Vec::new(),
+		})
 	}
 
 }
@@ -0,0 +1,89 @@
+use std::{
+    cell::Cell,
+    future::Future,
+    panic,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::task::JoinHandle;
+
+thread_local! {
+    /// Whether the thread is running a call made with [`spawn_call`], the blocking pool threads are reused for other tasks
+    static IN_SPAWNED_CALL: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the current thread is running a call made with [`spawn_call`], on the blocking pool
+pub(crate) fn in_spawned_call() -> bool {
+    IN_SPAWNED_CALL.with(Cell::get)
+}
+
+/// Clears the flag when the call returns or panics
+struct SpawnedCall;
+
+impl SpawnedCall {
+    fn enter() -> Self {
+        IN_SPAWNED_CALL.with(|c| c.set(true));
+        SpawnedCall
+    }
+}
+
+impl Drop for SpawnedCall {
+    fn drop(&mut self) {
+        IN_SPAWNED_CALL.with(|c| c.set(false));
+    }
+}
+
+/// Runs a plugin call on tokio's blocking pool, so that it doesn't block the executor.
+///
+/// If the returned future is dropped (eg. by `tokio::time::timeout`) before the call starts, the call is never made.
+/// A running wasm call can't be interrupted, so if it has already started it finishes in the background, and its result is dropped.
+pub fn spawn_call<F, R>(call: F) -> PluginCall<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let cancelled = cancelled.clone();
+        tokio::task::spawn_blocking(move || {
+            if cancelled.load(Ordering::Acquire) {
+                None
+            } else {
+                let _spawned = SpawnedCall::enter();
+                Some(call())
+            }
+        })
+    };
+    PluginCall { handle, cancelled }
+}
+
+/// A plugin call running on the blocking pool. Dropping it cancels the call if it hasn't started yet.
+pub struct PluginCall<R> {
+    handle: JoinHandle<Option<R>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<R> Future for PluginCall<R> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        match Pin::new(&mut self.handle).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(Some(r))) => Poll::Ready(r),
+            Poll::Ready(Ok(None)) => unreachable!("The call is only skipped once its future has been dropped"),
+            Poll::Ready(Err(e)) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+            Poll::Ready(Err(_)) => panic!("The plugin call was cancelled by the runtime shutting down"),
+        }
+    }
+}
+
+impl<R> Drop for PluginCall<R> {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Release)
+    }
+}
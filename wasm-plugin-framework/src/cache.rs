@@ -0,0 +1,215 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use wasmer::{Module, Store};
+
+const EXTENSION: &str = "wasmu";
+
+/// The extension of the entries being written
+const TMP_EXTENSION: &str = "tmp";
+
+/// The temporary files older than this are left by writers which crashed, no write takes that long
+const STALE_TMP: Duration = Duration::from_secs(60 * 60);
+
+/// An on-disk cache of compiled plugin modules.
+///
+/// Entries are keyed by a hash of the wasm bytes and a hash of the framework and wasmer versions, the compiler and its target,
+/// so an entry made by another version or for another CPU is never loaded.
+pub struct ModuleCache {
+    dir: PathBuf,
+    enabled: AtomicBool,
+}
+
+impl ModuleCache {
+    /// Opens (and creates if needed) the cache directory, removing the entries left half written by crashed processes
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let cache = Self {
+            dir,
+            enabled: AtomicBool::new(true),
+        };
+        let now = SystemTime::now();
+        cache.remove_files(TMP_EXTENSION, |_, modified| {
+            matches!(now.duration_since(modified), Ok(age) if age > STALE_TMP)
+        })?;
+        Ok(cache)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// While disabled, modules are neither loaded from nor stored in the cache
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Loads the cached module compiled from these bytes, if there is one
+    pub(crate) fn load(&self, store: &Store, compiler_settings: &str, bytes: &[u8]) -> Option<Module> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let serialized = fs::read(self.entry_path(compiler_settings, bytes)).ok()?;
+        // ! ###  Unsafe conditions not to be violated
+
+        // * The bytes must have been produced by Module::serialize, with the same wasmer version and compiler settings,
+        // * which is guaranteed by the entry key, as long as nobody else writes to the cache directory.
+        unsafe { Module::deserialize(store, &serialized) }.ok()
+    }
+
+    /// Stores the module compiled from these bytes.
+    /// Failing to store it is not an error, the module will just be compiled again the next time.
+    pub(crate) fn store(&self, compiler_settings: &str, bytes: &[u8], module: &Module) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Ok(serialized) = module.serialize() {
+            let path = self.entry_path(compiler_settings, bytes);
+            // Written to a temporary file first, so that a concurrent load never sees a half written entry
+            let tmp = path.with_extension(format!("{}.{}", std::process::id(), TMP_EXTENSION));
+            if fs::write(&tmp, serialized).is_ok() && fs::rename(&tmp, &path).is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+        }
+    }
+
+    /// Removes every entry (for any version or compiler settings) of the module compiled from these bytes, including the ones being written
+    pub fn invalidate(&self, bytes: &[u8]) -> io::Result<()> {
+        let prefix = format!("{}-", hex_hash(&[bytes]));
+        self.remove_files(EXTENSION, |name, _| name.starts_with(&prefix))?;
+        self.remove_files(TMP_EXTENSION, |name, _| name.starts_with(&prefix))
+    }
+
+    /// Removes every entry of the cache, including the ones being written
+    pub fn clear(&self) -> io::Result<()> {
+        self.remove_files(EXTENSION, |_, _| true)?;
+        self.remove_files(TMP_EXTENSION, |_, _| true)
+    }
+
+    /// Removes the files of the cache with the extension for which `f` returns true, given their name and modification time
+    fn remove_files<F: Fn(&str, SystemTime) -> bool>(&self, extension: &str, f: F) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !matches!(path.extension(), Some(e) if e == extension) {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            if matches!(path.file_name().and_then(|n| n.to_str()), Some(n) if f(n, modified)) {
+                // Another process may have removed or renamed it in the meantime
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, compiler_settings: &str, bytes: &[u8]) -> PathBuf {
+        let settings = hex_hash(&[env!("CARGO_PKG_VERSION").as_bytes(), compiler_settings.as_bytes()]);
+        self.dir
+            .join(format!("{}-{}", hex_hash(&[bytes]), &settings[..16]))
+            .with_extension(EXTENSION)
+    }
+}
+
+/// Hashes the parts (separated so that ("ab", "c") and ("a", "bc") don't collide) into a hex string
+pub(crate) fn hex_hash(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_plugins::{plugin, plugin_record};
+
+    fn files(cache: &ModuleCache) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(cache.dir())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn entries_are_keyed_by_bytes_and_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(dir.path()).unwrap();
+        let a = cache.entry_path("settings", b"a");
+        assert_eq!(a, cache.entry_path("settings", b"a"));
+        assert_ne!(a, cache.entry_path("settings", b"b"));
+        assert_ne!(a, cache.entry_path("other settings", b"a"));
+        assert_eq!(a.extension().unwrap(), EXTENSION);
+    }
+
+    #[test]
+    fn hashes_separate_the_parts() {
+        assert_ne!(hex_hash(&[b"ab", b"c"]), hex_hash(&[b"a", b"bc"]));
+        assert_eq!(hex_hash(&[b"a"]).len(), 64);
+    }
+
+    #[test]
+    fn stores_and_loads_modules() {
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = plugin("", &[plugin_record("cached", "0.1.0")]);
+        let store = Store::default();
+        let cache = ModuleCache::new(dir.path()).unwrap();
+        assert!(cache.load(&store, "settings", &bytes).is_none());
+
+        cache.store("settings", &bytes, &Module::new(&store, &bytes).unwrap());
+        assert_eq!(files(&cache).len(), 1);
+        assert!(cache.load(&store, "settings", &bytes).is_some());
+        assert!(cache.load(&store, "other settings", &bytes).is_none());
+
+        cache.set_enabled(false);
+        assert!(cache.load(&store, "settings", &bytes).is_none());
+    }
+
+    #[test]
+    fn invalidate_removes_the_entries_and_temporary_files_of_the_module() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(dir.path()).unwrap();
+        let kept = cache.entry_path("settings", b"b");
+        for path in [
+            cache.entry_path("settings", b"a"),
+            cache.entry_path("other settings", b"a"),
+            cache.entry_path("settings", b"a").with_extension(format!("1.{}", TMP_EXTENSION)),
+            kept.clone(),
+        ] {
+            fs::write(path, b"").unwrap();
+        }
+        fs::write(dir.path().join("unrelated"), b"").unwrap();
+
+        cache.invalidate(b"a").unwrap();
+        let kept = kept.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(files(&cache), [kept, "unrelated".to_string()]);
+        cache.clear().unwrap();
+        assert_eq!(files(&cache), ["unrelated"]);
+    }
+
+    #[test]
+    fn opening_keeps_the_entries_being_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = dir.path().join(format!("entry.1.{}", TMP_EXTENSION));
+        fs::write(&tmp, b"").unwrap();
+        ModuleCache::new(dir.path()).unwrap();
+        assert!(tmp.exists());
+    }
+}
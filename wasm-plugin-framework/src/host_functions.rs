@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use wasmer::{Exports, Function, LazyInit, Memory, NativeFunc, RuntimeError, Store, WasmerEnv};
+
+use crate::{
+    abi::{self, PluginLoader},
+    plugin_calls::{self, DependencyInstance},
+};
+
+/// The import module the host functions are provided in
+pub(crate) const IMPORT_MODULE: &str = "wasm_plugin_framework";
+
+/// A host function taking and returning encoded values, which fails with a message the plugin traps with
+type RawHostFunction = dyn Fn(&[u8]) -> Result<Vec<u8>, String> + Send + Sync;
+
+/// Functions the host provides to the plugins, which they call with `wasm_plugin_framework::call_host`.
+/// The arguments and results go through the same bincode ABI as the API functions.
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    fns: HashMap<String, Arc<RawHostFunction>>,
+}
+
+impl HostFunctions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a function, replacing any other with the same name
+    pub fn register<A, R, F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        A: for<'a> Deserialize<'a>,
+        R: Serialize,
+        F: Fn(A) -> R + Send + Sync + 'static,
+    {
+        self.fns.insert(
+            name.to_string(),
+            Arc::new(move |arg: &[u8]| Ok(abi::into_bytes(&f(abi::from_bytes(arg))))),
+        );
+        self
+    }
+
+    /// Registers a function returning a future, replacing any other with the same name.
+    ///
+    /// The plugin isn't suspended while the future runs: the thread making the plugin call waits for the future to resolve
+    /// on the tokio runtime of that thread. So the function can only be called from the plugin calls made with the `_async` methods
+    /// (or [`crate::spawn_call`]), which run on tokio's blocking pool, where waiting doesn't block the executor.
+    /// Called from any other plugin call, it fails and the plugin traps with an error.
+    #[cfg(feature = "async")]
+    pub fn register_async<A, R, F, Fut>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        A: for<'a> Deserialize<'a>,
+        R: Serialize,
+        F: Fn(A) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = R>,
+    {
+        let function = name.to_string();
+        self.fns.insert(
+            name.to_string(),
+            Arc::new(move |arg: &[u8]| {
+                if !crate::asynchronous::in_spawned_call() {
+                    return Err(format!(
+                        "The host function {} can only be called from the plugin calls made with the _async methods",
+                        function
+                    ));
+                }
+                let handle = tokio::runtime::Handle::try_current().map_err(|e| e.to_string())?;
+                Ok(abi::into_bytes(&handle.block_on(f(abi::from_bytes(arg)))))
+            }),
+        );
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.fns.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.fns.keys().map(String::as_str)
+    }
+
+    /// Calls the function with already encoded bytes, returning the encoded result, or `None` if there is no function with this name
+    pub(crate) fn call(&self, name: &str, arg: &[u8]) -> Option<Result<Vec<u8>, String>> {
+        self.fns.get(name).map(|f| f(arg))
+    }
+
+    /// The exports of the import module, for an instance of a module compiled in this store,
+    /// which can call the plugins it depends on
+    pub(crate) fn exports(self: &Arc<Self>, store: &Store, dependencies: Vec<DependencyInstance>) -> Exports {
+        let env = HostCallEnv {
+            host_functions: self.clone(),
+            dependencies: Arc::new(dependencies),
+            memory: LazyInit::new(),
+            allocate_buffer: LazyInit::new(),
+            free_buffer: LazyInit::new(),
+        };
+        let mut exports = Exports::new();
+        exports.insert("host_call", Function::new_native_with_env(store, env.clone(), host_call));
+        exports.insert(
+            "plugin_get",
+            Function::new_native_with_env(store, env.clone(), plugin_calls::plugin_get),
+        );
+        exports.insert(
+            "plugin_call",
+            Function::new_native_with_env(store, env, plugin_calls::plugin_call),
+        );
+        exports
+    }
+}
+
+/// The environment of the functions imported by a plugin instance
+#[derive(WasmerEnv, Clone)]
+pub(crate) struct HostCallEnv {
+    host_functions: Arc<HostFunctions>,
+    pub(crate) dependencies: Arc<Vec<DependencyInstance>>,
+    #[wasmer(export)]
+    memory: LazyInit<Memory>,
+    #[wasmer(export(name = "allocate_buffer"))]
+    allocate_buffer: LazyInit<NativeFunc<u32, u32>>,
+    #[wasmer(export(name = "free_buffer"))]
+    free_buffer: LazyInit<NativeFunc<(u32, u32), ()>>,
+}
+
+impl PluginLoader for HostCallEnv {
+    fn allocate_buffer(&self, size: u32) -> u32 {
+        self.allocate_buffer
+            .get_ref()
+            .expect("Tried to load a non plugin, which doesn't have the allocate_buffer function")
+            .call(size)
+            .expect("Unexpected error when calling allocate_buffer")
+    }
+
+    fn free_buffer(&self, ptr: u32, size: u32) {
+        self.free_buffer
+            .get_ref()
+            .expect("Tried to load a non plugin, which doesn't have the free_buffer function")
+            .call(ptr, size)
+            .expect("Unexpected error when calling free_buffer")
+    }
+
+    fn memory(&self) -> &Memory {
+        self.memory
+            .get_ref()
+            .expect("Expected a memory to be exported, are you sure this is a plugin?")
+    }
+}
+
+/// The function imported by the plugins. It traps instead of panicking, as a panic can't unwind through the wasm frames.
+fn host_call(env: &HostCallEnv, name_ptr: u32, name_len: u32, arg_ptr: u32) -> Result<u32, RuntimeError> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let name = abi::read_bytes(env.memory(), name_ptr, name_len)
+            .ok_or_else(|| RuntimeError::new("The plugin called a host function with a name outside of its memory"))?;
+        let name = String::from_utf8_lossy(&name);
+        let arg = abi::from_abi_bytes(env, arg_ptr);
+        match env.host_functions.call(&name, &arg) {
+            Some(Ok(res)) => Ok(abi::into_abi_bytes(env, &res)),
+            Some(Err(e)) => Err(RuntimeError::new(e)),
+            None => Err(RuntimeError::new(format!("The plugin called an unknown host function: {}", name))),
+        }
+    }))
+    .unwrap_or_else(|_| Err(RuntimeError::new("A host function panicked")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_the_functions_with_encoded_values() {
+        let mut host_functions = HostFunctions::new();
+        host_functions.register("double", |x: u32| x * 2);
+        let result = host_functions.call("double", &abi::into_bytes(&21u32)).unwrap().unwrap();
+        assert_eq!(abi::from_bytes::<u32>(&result), 42);
+        assert!(host_functions.call("missing", &[]).is_none());
+        assert_eq!(host_functions.names().collect::<Vec<_>>(), ["double"]);
+    }
+
+    #[test]
+    fn host_calls_with_names_outside_of_the_memory_trap() {
+        use crate::{
+            loader::{CompiledPlugin, Engine},
+            test_plugins::{api_record, plugin_importing, plugin_record},
+        };
+
+        let module = plugin_importing(
+            r#"(import "wasm_plugin_framework" "host_call" (func $host_call (param i32 i32 i32) (result i32)))"#,
+            r#"(func (export "call") (param i32 i32 i32) (result i32)
+                (call $host_call (local.get 0) (local.get 1) (local.get 2)))"#,
+            &[plugin_record("caller", "0.1.0"), api_record("caller", "0.1.0", &[])],
+        );
+        let instance = CompiledPlugin::new(&Engine::new(), &module).instantiate();
+        let args = [(-16).into(), 64.into(), 0.into()];
+        let error = instance.exports.get_function("call").unwrap().call(&args).unwrap_err();
+        assert!(error.message().contains("outside of its memory"), "{}", error.message());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_functions_can_only_be_called_from_spawned_calls() {
+        let mut host_functions = HostFunctions::new();
+        host_functions.register_async("double", |x: u32| async move { x * 2 });
+        let host_functions = Arc::new(host_functions);
+        let arg = abi::into_bytes(&21u32);
+        assert!(host_functions.call("double", &arg).unwrap().is_err());
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        // On an executor thread it fails instead of blocking the executor
+        assert!(runtime.block_on(async { host_functions.call("double", &arg) }).unwrap().is_err());
+        let result = runtime
+            .block_on(async { crate::spawn_call(move || host_functions.call("double", &arg)).await })
+            .unwrap()
+            .unwrap();
+        assert_eq!(abi::from_bytes::<u32>(&result), 42);
+    }
+}
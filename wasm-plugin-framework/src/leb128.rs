@@ -0,0 +1,92 @@
+//! The LEB128 numbers of the wasm binary format, used for the section sizes, counts and indices.
+//! Shared by everything reading or rewriting the modules: the metadata reader, the profiler and the CLI
+
+/// Reads an unsigned LEB128 number of at most 32 bits, advancing the bytes past it
+pub fn read(bytes: &mut &[u8]) -> Option<u32> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        // The last byte only has 4 bits left for a u32
+        if shift == 28 && byte & 0x70 != 0 {
+            return None;
+        }
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+    None
+}
+
+/// Writes an unsigned LEB128 number, in as few bytes as possible
+pub fn write(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Writes a signed LEB128 number, as used for the constants of the instructions
+pub fn write_signed(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for value in [0, 1, 127, 128, 300, 16_384, u32::MAX] {
+            let mut out = Vec::new();
+            write(&mut out, value);
+            let mut bytes = out.as_slice();
+            assert_eq!(read(&mut bytes), Some(value));
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn encodings() {
+        let encode = |value| {
+            let mut out = Vec::new();
+            write(&mut out, value);
+            out
+        };
+        assert_eq!(encode(127), [0x7f]);
+        assert_eq!(encode(624_485), [0xe5, 0x8e, 0x26]);
+        let encode_signed = |value| {
+            let mut out = Vec::new();
+            write_signed(&mut out, value);
+            out
+        };
+        assert_eq!(encode_signed(-1), [0x7f]);
+        assert_eq!(encode_signed(64), [0xc0, 0x00]);
+        assert_eq!(encode_signed(-123_456), [0xc0, 0xbb, 0x78]);
+    }
+
+    #[test]
+    fn rejects_truncated_and_too_long_numbers() {
+        assert_eq!(read(&mut [0x80, 0x80].as_slice()), None);
+        assert_eq!(read(&mut [].as_slice()), None);
+        // 6 bytes, or a 5th byte with more than 32 bits
+        assert_eq!(read(&mut [0x80, 0x80, 0x80, 0x80, 0x80, 0x00].as_slice()), None);
+        assert_eq!(read(&mut [0xff, 0xff, 0xff, 0xff, 0x1f].as_slice()), None);
+        assert_eq!(read(&mut [0xff, 0xff, 0xff, 0xff, 0x0f].as_slice()), Some(u32::MAX));
+    }
+}
@@ -5,3 +5,66 @@ pub use wasmer;
 pub use wasmer_wasi;
 #[doc(hidden)]
 pub mod abi;
+#[cfg(target_arch = "wasm32")]
+pub use abi::{call_host, PluginHandle};
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+mod asynchronous;
+#[cfg(not(target_arch = "wasm32"))]
+mod cache;
+#[cfg(not(target_arch = "wasm32"))]
+mod host_functions;
+#[cfg(not(target_arch = "wasm32"))]
+#[doc(hidden)]
+pub mod leb128;
+#[cfg(not(target_arch = "wasm32"))]
+mod loader;
+#[cfg(not(target_arch = "wasm32"))]
+mod manager;
+#[cfg(not(target_arch = "wasm32"))]
+mod metadata;
+#[cfg(not(target_arch = "wasm32"))]
+mod package;
+#[cfg(not(target_arch = "wasm32"))]
+mod plugin_calls;
+#[cfg(not(target_arch = "wasm32"))]
+mod pool;
+#[cfg(not(target_arch = "wasm32"))]
+mod profiling;
+#[cfg(not(target_arch = "wasm32"))]
+mod recording;
+#[cfg(not(target_arch = "wasm32"))]
+mod shared;
+#[cfg(not(target_arch = "wasm32"))]
+mod stats;
+#[cfg(test)]
+mod test_plugins;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testing;
+#[cfg(not(target_arch = "wasm32"))]
+mod trust;
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+pub use asynchronous::{spawn_call, PluginCall};
+#[cfg(not(target_arch = "wasm32"))]
+pub use cache::ModuleCache;
+#[cfg(not(target_arch = "wasm32"))]
+pub use host_functions::HostFunctions;
+#[cfg(not(target_arch = "wasm32"))]
+pub use loader::{ApiFunction, ApiLoader, CompiledPlugin, Engine, PluginInstance};
+#[cfg(not(target_arch = "wasm32"))]
+pub use manager::{LoadError, ManagedPlugin, PluginManager};
+#[cfg(not(target_arch = "wasm32"))]
+pub use metadata::{custom_section_sizes, Dependency, DependencyKind, Incompatibility, MetadataError, PluginApi, PluginInfo, PluginMetadata};
+#[cfg(not(target_arch = "wasm32"))]
+pub use package::{PackageError, PackageManifest, PluginPackage, ASSETS_DIR, PACKAGE_EXTENSION};
+#[cfg(not(target_arch = "wasm32"))]
+pub use pool::{PluginPool, PoolExhausted, PooledPlugin};
+#[cfg(not(target_arch = "wasm32"))]
+pub use profiling::{FunctionProfile, Profile};
+#[cfg(not(target_arch = "wasm32"))]
+pub use recording::{read_recording, replay, CallRecorder, RecordedCall, ReplayMismatch};
+#[cfg(not(target_arch = "wasm32"))]
+pub use shared::SharedPlugin;
+#[cfg(not(target_arch = "wasm32"))]
+pub use stats::{FunctionStats, LatencyHistogram, PluginStats};
+#[cfg(not(target_arch = "wasm32"))]
+pub use trust::{module_hash, InvalidTrustEntry, TrustPolicy, Untrusted};
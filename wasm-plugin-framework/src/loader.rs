@@ -0,0 +1,780 @@
+use std::{
+    borrow::Cow,
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use wasmer::{Instance, Memory, Module, Store, Val};
+use wasmer_wasi::WasiState;
+
+use crate::{
+    abi::{self, PluginLoader, ABI_VERSION},
+    cache::ModuleCache,
+    host_functions::{HostFunctions, IMPORT_MODULE},
+    leb128,
+    manager::LoadError,
+    metadata::{MetadataError, PluginApi, PluginInfo, PluginMetadata, SECTION},
+    package::{ExtractedAssets, PackageManifest, PluginPackage, ASSETS_DIR},
+    plugin_calls::DependencyInstance,
+    profiling::{self, Profile},
+    recording::{CallRecorder, RecordedCall},
+    stats::{CallTimings, PluginStats, StatsCollector},
+    trust::{TrustPolicy, Untrusted},
+};
+
+/// A wasm engine, which can be shared between all the plugins of a process.
+/// Cloning it is cheap, as all the clones use the same underlying engine (and cache).
+#[derive(Clone)]
+pub struct Engine {
+    store: Store,
+    compiler_settings: String,
+    cache: Option<Arc<ModuleCache>>,
+    host_functions: Arc<HostFunctions>,
+    profiling: bool,
+    trust_policy: Option<Arc<TrustPolicy>>,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        let store = Store::default();
+        // The target has the CPU features the code is compiled for, besides the triple
+        let compiler_settings = format!("wasmer-{}-cranelift-jit-{:?}", wasmer::VERSION, store.engine().target());
+        Self {
+            store,
+            compiler_settings,
+            cache: None,
+            host_functions: Arc::new(HostFunctions::new()),
+            profiling: false,
+            trust_policy: None,
+        }
+    }
+
+    /// Creates an engine which stores the compiled modules in `dir`, and loads them from there instead of compiling them again.
+    /// The compiled modules are loaded without any check, so the cache isn't used once the engine has a trust policy
+    pub fn with_cache<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        Ok(Self {
+            cache: Some(Arc::new(ModuleCache::new(dir)?)),
+            ..Self::new()
+        })
+    }
+
+    /// Sets the host functions provided to the plugins compiled with this engine
+    pub fn with_host_functions(self, host_functions: HostFunctions) -> Self {
+        Self {
+            host_functions: Arc::new(host_functions),
+            ..self
+        }
+    }
+
+    /// Instruments the plugins compiled with this engine to count the calls and the instructions run by each of their wasm functions,
+    /// which can be read with `CompiledPlugin::profile`. It slows the plugins down, so it should only be used to profile them
+    pub fn with_profiling(self) -> Self {
+        Self {
+            compiler_settings: format!("{}-profiling", self.compiler_settings),
+            profiling: true,
+            ..self
+        }
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profiling
+    }
+
+    /// Only compiles the plugins the policy trusts, the others fail to load with [`Untrusted`].
+    /// The plugins are always compiled from their checked bytes, as anyone who can write to the cache directory could replace a cached module
+    pub fn with_trust_policy(self, policy: TrustPolicy) -> Self {
+        Self {
+            trust_policy: Some(Arc::new(policy)),
+            ..self
+        }
+    }
+
+    pub fn trust_policy(&self) -> Option<&TrustPolicy> {
+        self.trust_policy.as_deref()
+    }
+
+    /// Checks the module against the trust policy, if the engine has one
+    pub fn verify(&self, bytes: &[u8], signature: Option<&[u8]>) -> Result<(), Untrusted> {
+        match self.trust_policy() {
+            Some(policy) => policy.verify(bytes, signature),
+            None => Ok(()),
+        }
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn cache(&self) -> Option<&ModuleCache> {
+        self.cache.as_deref()
+    }
+
+    pub fn host_functions(&self) -> &HostFunctions {
+        &self.host_functions
+    }
+
+    /// The cache the modules go through, unless the trust policy rules it out
+    fn usable_cache(&self) -> Option<&ModuleCache> {
+        self.cache().filter(|_| self.trust_policy.is_none())
+    }
+
+    /// Compiles the module, going through the cache if there is one. The module isn't checked against the trust policy,
+    /// which the callers do first
+    pub(crate) fn compile(&self, bytes: &[u8]) -> Result<Module, LoadError> {
+        if let Some(module) = self
+            .usable_cache()
+            .and_then(|c| c.load(&self.store, &self.compiler_settings, bytes))
+        {
+            return Ok(module);
+        }
+        let module = if self.profiling {
+            let instrumented = profiling::instrument(bytes)
+                .map_err(|e| LoadError::InvalidPlugin(format!("couldn't instrument the module to profile it: {}", e)))?;
+            Module::new(&self.store, pad_signatures(&instrumented))
+        } else {
+            Module::new(&self.store, pad_signatures(bytes))
+        }
+        .map_err(|e| LoadError::InvalidPlugin(format!("couldn't compile the module: {}", e)))?;
+        if let Some(cache) = self.usable_cache() {
+            cache.store(&self.compiler_settings, bytes, &module);
+        }
+        Ok(module)
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Adds an unused signature to the type section of the module if it has an odd number of them.
+///
+/// Wasmer lays out the 4 byte signature ids of an instance right before its imported functions,
+/// which are then misaligned when there is an odd number of signatures: copying them at instantiation is undefined behavior,
+/// which debug builds abort on. The modules which can't be parsed are left as they are, for the compiler to report the error
+fn pad_signatures(bytes: &[u8]) -> Cow<'_, [u8]> {
+    const SECTION_TYPE: u8 = 1;
+    let mut rest = match bytes {
+        [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, rest @ ..] => rest,
+        _ => return Cow::Borrowed(bytes),
+    };
+    while let Some((&id, after_id)) = rest.split_first() {
+        let mut section = after_id;
+        let len = match leb128::read(&mut section) {
+            Some(len) if section.len() >= len as usize => len as usize,
+            _ => return Cow::Borrowed(bytes),
+        };
+        if id == SECTION_TYPE {
+            let mut types = &section[..len];
+            match leb128::read(&mut types) {
+                Some(count) if count % 2 == 1 => {
+                    let mut padded = Vec::new();
+                    leb128::write(&mut padded, count + 1);
+                    padded.extend_from_slice(types);
+                    // A function without parameters nor results
+                    padded.extend_from_slice(&[0x60, 0x00, 0x00]);
+                    let start = bytes.len() - rest.len();
+                    let mut module = bytes[..start].to_vec();
+                    module.push(SECTION_TYPE);
+                    leb128::write(&mut module, padded.len() as u32);
+                    module.extend(padded);
+                    module.extend_from_slice(&section[len..]);
+                    return Cow::Owned(module);
+                }
+                _ => return Cow::Borrowed(bytes),
+            }
+        }
+        rest = &section[len..];
+    }
+    Cow::Borrowed(bytes)
+}
+
+/// The prefix of all the symbols the plugins export for the APIs they implement
+const SYMBOL_PREFIX: &str = "__wpf_";
+
+/// Implemented by the loaders generated for each API, so that they can be bound to an instance of any plugin providing it
+pub trait ApiLoader: Sized {
+    const API_NAME: &'static str;
+    const API_VERSION: &'static str;
+
+    /// Binds the loader to an existing instance of the plugin. Panics if the plugin doesn't provide the API
+    fn from_instance(compiled: &CompiledPlugin, instance: &PluginInstance) -> Self;
+}
+
+/// An API function, as the generated loaders call it
+#[doc(hidden)]
+pub struct ApiFunction {
+    pub api_name: &'static str,
+    pub api_version: &'static str,
+    pub name: &'static str,
+    pub symbol: &'static str,
+}
+
+/// A compiled plugin module, with its metadata (the APIs it implements, plugin name) already read.
+/// Instantiating it doesn't compile the module again, so it can cheaply spawn many independent instances.
+#[derive(Clone)]
+pub struct CompiledPlugin {
+    module: Module,
+    host_functions: Arc<HostFunctions>,
+    metadata: PluginMetadata,
+    /// The manifest of the package the plugin was loaded from
+    manifest: Option<PackageManifest>,
+    /// The directory with the package assets, mapped into the WASI filesystem of each instance, and kept until they are all dropped
+    assets: Option<Arc<ExtractedAssets>>,
+    recorder: Option<CallRecorder>,
+    stats: StatsCollector,
+}
+
+impl CompiledPlugin {
+    /// Compiles the module (or loads it from the engine's cache) and reads its metadata.
+    /// Panics if the module isn't a valid plugin, or if the engine has a trust policy the module doesn't satisfy
+    pub fn new(engine: &Engine, bytes: &[u8]) -> Self {
+        Self::verified(engine, bytes, None).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Checks the module and its detached signature against the engine's trust policy, and compiles it if it is trusted
+    pub fn verified(engine: &Engine, bytes: &[u8], signature: Option<&[u8]>) -> Result<Self, LoadError> {
+        engine.verify(bytes, signature).map_err(LoadError::Untrusted)?;
+        Self::from_module_unchecked(engine, engine.compile(bytes)?)
+    }
+
+    /// Reads the metadata of an already compiled module, which isn't checked against the engine's trust policy:
+    /// the caller has to make sure the module is trusted
+    fn from_module_unchecked(engine: &Engine, module: Module) -> Result<Self, LoadError> {
+        let host_functions = engine.host_functions.clone();
+        let metadata = match PluginMetadata::from_sections(module.custom_sections(SECTION)) {
+            Ok(metadata) => metadata,
+            // The section may have been stripped by a tool, the exports are still there
+            Err(MetadataError::Missing) => read_exported_metadata(&module, &host_functions).map_err(LoadError::InvalidPlugin)?,
+            Err(e) => return Err(LoadError::InvalidPlugin(e.to_string())),
+        };
+        metadata
+            .check_abi_version()
+            .map_err(|e| LoadError::InvalidPlugin(e.to_string()))?;
+
+        Ok(Self {
+            module,
+            host_functions,
+            metadata,
+            manifest: None,
+            assets: None,
+            recorder: None,
+            stats: StatsCollector::default(),
+        })
+    }
+
+    /// Compiles the module of a package, whose instances see the package assets as a read-only directory at [`ASSETS_DIR`].
+    /// Panics if the module isn't a valid plugin, or if the engine has a trust policy the package doesn't satisfy
+    pub fn from_package(engine: &Engine, package: &PluginPackage) -> Self {
+        Self::verified_from_package(engine, package).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Checks the contents of a package (its manifest, module and assets) and its signature against the engine's trust policy,
+    /// and compiles the module if they are trusted
+    pub fn verified_from_package(engine: &Engine, package: &PluginPackage) -> Result<Self, LoadError> {
+        engine
+            .verify(&package.signed_contents(), package.signature())
+            .map_err(LoadError::Untrusted)?;
+        let compiled = Self::from_module_unchecked(engine, engine.compile(package.module())?)?;
+        let assets = package
+            .extract_assets()
+            .map_err(|e| LoadError::Assets(e.to_string()))?
+            .map(Arc::new);
+        Ok(Self {
+            manifest: Some(package.manifest().clone()),
+            assets,
+            ..compiled
+        })
+    }
+
+    /// Records the API calls made through the loaders of this plugin (including the ones created from it afterwards)
+    pub fn with_recorder(self, recorder: CallRecorder) -> Self {
+        Self {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    pub fn recorder(&self) -> Option<&CallRecorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Creates a new instance of the plugin, with its own memory and WASI environment
+    pub fn instantiate(&self) -> PluginInstance {
+        self.instance(Vec::new())
+    }
+
+    /// Creates a new instance of the plugin, which can call the instances of the plugins it depends on
+    pub(crate) fn instantiate_with_dependencies(&self, dependencies: Vec<DependencyInstance>) -> PluginInstance {
+        self.instance(dependencies)
+    }
+
+    fn instance(&self, dependencies: Vec<DependencyInstance>) -> PluginInstance {
+        let assets = self.assets.as_deref().map(ExtractedAssets::path);
+        PluginInstance::new(instantiate(&self.module, &self.host_functions, assets, dependencies), self.assets.clone())
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    pub fn metadata(&self) -> &PluginMetadata {
+        &self.metadata
+    }
+
+    /// The APIs the plugin implements
+    pub fn apis(&self) -> &[PluginApi] {
+        &self.metadata.apis
+    }
+
+    pub fn provides(&self, api_name: &str, api_version: &str) -> bool {
+        self.metadata.provides(api_name, api_version)
+    }
+
+    pub fn plugin_name(&self) -> &str {
+        &self.metadata.info.name
+    }
+
+    pub fn info(&self) -> &PluginInfo {
+        &self.metadata.info
+    }
+
+    /// The manifest of the package the plugin was loaded from, if it was
+    pub fn package_manifest(&self) -> Option<&PackageManifest> {
+        self.manifest.as_ref()
+    }
+
+    /// A snapshot of the statistics of the API calls made through the loaders of this plugin
+    pub fn stats(&self) -> PluginStats {
+        self.stats.snapshot(self.plugin_name())
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// The calls and instructions run by each wasm function of an instance of this plugin, if it was compiled by an engine with profiling
+    pub fn profile(&self, instance: &Instance) -> Option<Profile> {
+        Profile::read(self.plugin_name(), &self.module, instance)
+    }
+
+    /// Sets the profile counters of an instance of this plugin back to zero
+    pub fn reset_profile(&self, instance: &Instance) {
+        Profile::reset(&self.module, instance)
+    }
+
+    /// Calls an API function on an instance of this plugin, encoding the arguments with `encode` and decoding the result with `decode`.
+    /// The arguments are only encoded once, the recorder gets the same bytes which are copied into the plugin memory.
+    /// The instance is locked from copying the arguments to reading the result.
+    /// Each call is traced with a span, and the time spent in each phase goes to the plugin statistics
+    #[doc(hidden)]
+    pub fn call_function<P, E, D, R>(&self, plugin_loader: &P, instance: &PluginInstance, function: &ApiFunction, encode: E, decode: D) -> R
+    where
+        P: PluginLoader,
+        E: FnOnce() -> Vec<Vec<u8>>,
+        D: FnOnce(&[u8]) -> R,
+    {
+        let span = tracing::debug_span!(
+            "plugin_call",
+            plugin = self.plugin_name(),
+            api = function.api_name,
+            api_version = function.api_version,
+            function = function.name,
+            args_size = tracing::field::Empty,
+            result_size = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let args = encode();
+        let args_size: usize = args.iter().map(Vec::len).sum();
+        span.record("args_size", args_size);
+        let encoded = Instant::now();
+        let guard = instance.lock();
+        let locked = Instant::now();
+        let result = instance
+            .exports
+            .get_function(function.symbol)
+            .map_err(|e| e.to_string())
+            .and_then(|f| {
+                let ptrs: Vec<Val> = args
+                    .iter()
+                    .map(|arg| (abi::into_abi_bytes(plugin_loader, arg) as i32).into())
+                    .collect();
+                let serialized = Instant::now();
+                let results = f.call(&ptrs).map_err(|e| e.message())?;
+                let returned = Instant::now();
+                // Functions without a result return nothing, which is encoded as an empty buffer like `()`
+                let result = match results.first() {
+                    Some(ptr) => abi::from_abi_bytes(plugin_loader, ptr.unwrap_i32() as u32),
+                    None => Vec::new(),
+                };
+                Ok((result, serialized, returned))
+            });
+        drop(guard);
+        let duration = start.elapsed();
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&RecordedCall {
+                plugin: self.metadata.info.name.clone(),
+                plugin_version: self.metadata.info.version.clone(),
+                api_name: function.api_name.to_string(),
+                api_version: function.api_version.to_string(),
+                function: function.name.to_string(),
+                args,
+                result: result.as_ref().map(|(result, _, _)| result.clone()).map_err(Clone::clone),
+                duration,
+            });
+        }
+
+        let (result, serialized, returned) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.stats
+                    .record_error(function.api_name, function.api_version, function.name, args_size);
+                tracing::error!(error = %e, "plugin call failed");
+                panic!("Unexpected error while calling the API function {}: {}", function.name, e)
+            }
+        };
+        span.record("result_size", result.len());
+        let value = decode(&result);
+        let timings = CallTimings {
+            serialize: (encoded - start) + (serialized - locked),
+            lock_wait: locked - encoded,
+            guest: returned - serialized,
+            deserialize: returned.elapsed(),
+        };
+        self.stats.record(
+            function.api_name,
+            function.api_version,
+            function.name,
+            args_size,
+            result.len(),
+            &timings,
+        );
+        value
+    }
+}
+
+/// An instance of a plugin, with its own memory and WASI environment.
+///
+/// Cloning it is cheap, and the clones are the same instance: the loaders of all the APIs the plugin implements can be bound to it,
+/// and the plugins depending on it call it. The calls through any of them lock the instance, as they write to its memory,
+/// so the loaders bound to the same instance can be used from different threads, one call at a time.
+#[derive(Clone)]
+pub struct PluginInstance {
+    instance: Instance,
+    lock: Arc<Mutex<()>>,
+    /// The assets the instance reads, which may outlive the compiled plugin
+    _assets: Option<Arc<ExtractedAssets>>,
+}
+
+impl PluginInstance {
+    fn new(instance: Instance, assets: Option<Arc<ExtractedAssets>>) -> Self {
+        Self {
+            instance,
+            lock: Arc::new(Mutex::new(())),
+            _assets: assets,
+        }
+    }
+
+    /// Waits for the calls made on the instance from other threads, and keeps the others from starting until the guard is dropped.
+    /// The API calls lock the instance themselves, this is only needed to use its memory directly
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        // The lock doesn't guard any host data, a call which panicked while holding it leaves nothing half updated
+        self.lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Deref for PluginInstance {
+    type Target = Instance;
+
+    fn deref(&self) -> &Instance {
+        &self.instance
+    }
+}
+
+/// The buffers of an instance, which the arguments are copied into and the results are read from
+pub(crate) struct InstanceLoader<'a>(pub(crate) &'a Instance);
+
+impl<'a> PluginLoader for InstanceLoader<'a> {
+    fn allocate_buffer(&self, size: u32) -> u32 {
+        self.0
+            .exports
+            .get_function("allocate_buffer")
+            .expect("Tried to load a non plugin, which doesn't have the allocate_buffer function")
+            .call(&[(size as i32).into()])
+            .expect("Unexpected error when calling allocate_buffer")[0]
+            .unwrap_i32() as u32
+    }
+
+    fn free_buffer(&self, ptr: u32, size: u32) {
+        self.0
+            .exports
+            .get_function("free_buffer")
+            .expect("Tried to load a non plugin, which doesn't have the free_buffer function")
+            .call(&[(ptr as i32).into(), (size as i32).into()])
+            .expect("Unexpected error when calling free_buffer");
+    }
+
+    fn memory(&self) -> &Memory {
+        self.0
+            .exports
+            .get_memory("memory")
+            .expect("Expected a memory to be exported, are you sure this is a plugin?")
+    }
+}
+
+fn instantiate(
+    module: &Module,
+    host_functions: &Arc<HostFunctions>,
+    assets: Option<&Path>,
+    dependencies: Vec<DependencyInstance>,
+) -> Instance {
+    try_instantiate(module, host_functions, assets, dependencies).unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn try_instantiate(
+    module: &Module,
+    host_functions: &Arc<HostFunctions>,
+    assets: Option<&Path>,
+    dependencies: Vec<DependencyInstance>,
+) -> Result<Instance, String> {
+    let mut wasi_state = WasiState::new("plugin");
+    if let Some(assets) = assets {
+        wasi_state
+            .preopen(|p| p.directory(assets).alias(ASSETS_DIR).read(true).write(false).create(false))
+            .map_err(|e| format!("Error mapping the plugin assets into the WASI environment: {}", e))?;
+    }
+    let mut wasi_env = wasi_state
+        .finalize()
+        .map_err(|e| format!("Error finalizing the WASI environment: {}", e))?;
+
+    // We get the import object related to our WASI and attach it to the Wasm instance.
+    let mut import_object = wasi_env
+        .import_object(module)
+        .map_err(|e| format!("Error creating the WASI import object based on the wasm module: {}", e))?;
+    import_object.register(IMPORT_MODULE, host_functions.exports(module.store(), dependencies));
+    Instance::new(module, &import_object).map_err(|e| format!("Error creating the WASM module instance: {}", e))
+}
+
+/// Reads the metadata from the functions each API exports under its own prefix, which requires instantiating the module.
+/// Only the plugin name and the APIs are exported this way
+fn read_exported_metadata(module: &Module, host_functions: &Arc<HostFunctions>) -> Result<PluginMetadata, String> {
+    let instance = try_instantiate(module, host_functions, None, Vec::new())?;
+    let prefixes: Vec<String> = module
+        .exports()
+        .functions()
+        .filter_map(|f| {
+            let name = f.name();
+            if name.starts_with(SYMBOL_PREFIX) {
+                name.strip_suffix("api_name").map(str::to_string)
+            } else {
+                None
+            }
+        })
+        .collect();
+    if prefixes.is_empty() {
+        return Err("Tried to load a non plugin, which doesn't implement any API".to_string());
+    }
+    let apis = prefixes
+        .iter()
+        .map(|prefix| {
+            Ok(PluginApi {
+                name: read_metadata_str(&instance, &format!("{}api_name", prefix))?,
+                version: read_metadata_str(&instance, &format!("{}api_version", prefix))?,
+                // The table has a `name=symbol` line per function
+                functions: read_metadata_str(&instance, &format!("{}exports", prefix))?
+                    .lines()
+                    .filter_map(|l| l.split('=').next())
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(PluginMetadata {
+        // The exports predate the ABI versioning, which started at its first version
+        abi_version: ABI_VERSION,
+        info: PluginInfo {
+            name: read_metadata_str(&instance, &format!("{}plugin_name", prefixes[0]))?,
+            ..PluginInfo::default()
+        },
+        apis,
+        dependencies: Vec::new(),
+    })
+}
+
+/// Calls one of the metadata functions (eg. the API name), which return a pointer to a null terminated string, and reads that string
+fn read_metadata_str(instance: &Instance, name: &str) -> Result<String, String> {
+    let m = instance
+        .exports
+        .get_memory("memory")
+        .map_err(|_| "Expected a memory to be exported, are you sure this is a plugin?".to_string())?;
+    let ptr = instance
+        .exports
+        .get_function(name)
+        .map_err(|_| format!("Tried to load a non plugin, which doesn't have the {} function", name))?
+        .call(&[])
+        .map_err(|e| format!("Unexpected error when calling {}: {}", name, e))?
+        .first()
+        .and_then(Val::i32)
+        .ok_or_else(|| format!("{} doesn't return a pointer", name))? as usize;
+
+    let bytes: Vec<u8> = m
+        .view()
+        .iter()
+        .skip(ptr)
+        .take_while(|x| {
+            let v: u8 = x.get();
+            v != 0
+        })
+        .map(|x| x.get())
+        .collect();
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_plugins::{api_record, plugin, plugin_record};
+
+    fn counter() -> Vec<u8> {
+        plugin(
+            r#"(global $count (mut i32) (i32.const 0))
+            (func (export "__wpf__counter__1_2e0_2e0__fn_increment") (result i32)
+                (global.set $count (i32.add (global.get $count) (i32.const 1)))
+                (global.get $count))"#,
+            &[plugin_record("counter", "0.1.0"), api_record("counter", "1.0.0", &["increment"])],
+        )
+    }
+
+    fn increment(instance: &Instance) -> i32 {
+        let f = instance.exports.get_function("__wpf__counter__1_2e0_2e0__fn_increment").unwrap();
+        f.call(&[]).unwrap()[0].unwrap_i32()
+    }
+
+    #[test]
+    fn reads_the_metadata_section() {
+        let compiled = CompiledPlugin::new(&Engine::new(), &counter());
+        assert_eq!(compiled.plugin_name(), "counter");
+        assert_eq!(compiled.info().version, "0.1.0");
+        assert!(compiled.provides("counter", "1.0.0"));
+        assert!(!compiled.provides("counter", "2.0.0"));
+    }
+
+    #[test]
+    fn instances_of_a_compiled_plugin_are_independent() {
+        let compiled = CompiledPlugin::new(&Engine::new(), &counter());
+        let a = compiled.instantiate();
+        let b = compiled.instantiate();
+        assert_eq!(increment(&a), 1);
+        assert_eq!(increment(&a), 2);
+        assert_eq!(increment(&b), 1);
+    }
+
+    #[test]
+    fn clones_of_an_instance_share_its_lock() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PluginInstance>();
+
+        let compiled = CompiledPlugin::new(&Engine::new(), &counter());
+        let a = compiled.instantiate();
+        let b = a.clone();
+        let guard = a.lock();
+        assert!(b.lock.try_lock().is_err());
+        assert!(compiled.instantiate().lock.try_lock().is_ok());
+        drop(guard);
+        assert_eq!(increment(&a), 1);
+        assert_eq!(increment(&b), 2);
+    }
+
+    #[test]
+    fn clones_of_an_engine_share_the_store() {
+        let engine = Engine::new();
+        let compiled = CompiledPlugin::new(&engine.clone(), &counter());
+        assert!(Store::same(compiled.module().store(), engine.store()));
+    }
+
+    #[test]
+    fn waiting_for_the_instance_lock_isnt_counted_as_serializing() {
+        let module = plugin(
+            r#"(func (export "__wpf__counter__1_2e0_2e0__fn_noop"))"#,
+            &[plugin_record("counter", "0.1.0"), api_record("counter", "1.0.0", &["noop"])],
+        );
+        let compiled = CompiledPlugin::new(&Engine::new(), &module);
+        let instance = compiled.instantiate();
+        let function = ApiFunction {
+            api_name: "counter",
+            api_version: "1.0.0",
+            name: "noop",
+            symbol: "__wpf__counter__1_2e0_2e0__fn_noop",
+        };
+        let wait = std::time::Duration::from_millis(50);
+        std::thread::scope(|scope| {
+            let guard = instance.lock();
+            scope.spawn(|| compiled.call_function(&InstanceLoader(&instance), &instance, &function, Vec::new, |_| ()));
+            std::thread::sleep(wait);
+            drop(guard);
+        });
+
+        let stats = compiled.stats();
+        let noop = stats.function("noop").unwrap();
+        assert!(noop.lock_wait.min().unwrap() >= wait);
+        assert!(noop.serialize.max().unwrap() < wait);
+    }
+
+    fn type_count(module: &[u8]) -> u32 {
+        use wasmer::wasmparser::{Parser, Payload};
+
+        Parser::new(0)
+            .parse_all(module)
+            .find_map(|payload| match payload {
+                Ok(Payload::TypeSection(reader)) => Some(reader.get_count()),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn plugins_with_an_odd_number_of_signatures_are_instantiated() {
+        let module = plugin(
+            r#"(type (func (param f64)))
+            (func (export "__wpf__counter__1_2e0_2e0__fn_increment") (result i32)
+                (i32.const 1))"#,
+            &[plugin_record("counter", "0.1.0"), api_record("counter", "1.0.0", &["increment"])],
+        );
+        assert_eq!(type_count(&module) % 2, 1);
+        let padded = pad_signatures(&module);
+        assert_eq!(type_count(&padded), type_count(&module) + 1);
+        wasmer::wasmparser::validate(&padded).unwrap();
+        assert_eq!(increment(&CompiledPlugin::new(&Engine::new(), &module).instantiate()), 1);
+        // The other modules are left as they are
+        assert!(matches!(pad_signatures(&padded), Cow::Borrowed(_)));
+        assert!(matches!(pad_signatures(b"not wasm"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn invalid_plugins_fail_to_load() {
+        let engine = Engine::new();
+        for bytes in [b"not wasm".to_vec(), wat::parse_str("(module)").unwrap()].iter() {
+            assert!(matches!(CompiledPlugin::verified(&engine, bytes, None), Err(LoadError::InvalidPlugin(_))));
+        }
+        let untrusted = CompiledPlugin::verified(&engine.with_trust_policy(TrustPolicy::new()), &counter(), None);
+        assert!(matches!(untrusted, Err(LoadError::Untrusted(_))));
+    }
+
+    #[test]
+    fn engines_with_a_trust_policy_dont_use_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = || std::fs::read_dir(dir.path()).unwrap().count();
+        let engine = Engine::with_cache(dir.path()).unwrap();
+        let policy = TrustPolicy::new().with_pinned_hash(&crate::module_hash(&counter())).unwrap();
+        CompiledPlugin::new(&engine.clone().with_trust_policy(policy), &counter());
+        assert_eq!(entries(), 0);
+        CompiledPlugin::new(&engine, &counter());
+        assert_eq!(entries(), 1);
+    }
+}
@@ -0,0 +1,427 @@
+use std::fmt;
+
+use semver::{Version, VersionReq};
+use crate::{
+    loader::{ApiLoader, CompiledPlugin, Engine, PluginInstance},
+    metadata::{Dependency, DependencyKind, PluginApi, PluginMetadata},
+    package::{PackageManifest, PluginPackage},
+    plugin_calls::DependencyInstance,
+    profiling::Profile,
+    stats::PluginStats,
+    trust::Untrusted,
+};
+
+/// Loads plugins with a shared engine, keeping one instance of each.
+/// A plugin can implement many APIs, the loaders of all of them are bound to that instance.
+/// The plugins are loaded after the plugins they depend on, which they can call with `PluginHandle`.
+pub struct PluginManager {
+    engine: Engine,
+    plugins: Vec<ManagedPlugin>,
+}
+
+impl PluginManager {
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            engine,
+            plugins: Vec::new(),
+        }
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Compiles and instantiates a plugin, whose dependencies must already be loaded
+    pub fn load(&mut self, bytes: &[u8]) -> Result<&ManagedPlugin, LoadError> {
+        let compiled = CompiledPlugin::verified(&self.engine, bytes, None)?;
+        self.add(compiled)
+    }
+
+    /// Like [`PluginManager::load`], with the detached signature of the module checked against the engine's trust policy
+    pub fn load_signed(&mut self, bytes: &[u8], signature: &[u8]) -> Result<&ManagedPlugin, LoadError> {
+        let compiled = CompiledPlugin::verified(&self.engine, bytes, Some(signature))?;
+        self.add(compiled)
+    }
+
+    /// Compiles and instantiates the plugin of a package, whose dependencies must already be loaded
+    pub fn load_package(&mut self, package: &PluginPackage) -> Result<&ManagedPlugin, LoadError> {
+        let compiled = CompiledPlugin::verified_from_package(&self.engine, package)?;
+        self.add(compiled)
+    }
+
+    /// Instantiates an already compiled plugin, whose dependencies must already be loaded
+    pub fn add(&mut self, compiled: CompiledPlugin) -> Result<&ManagedPlugin, LoadError> {
+        self.add_all(vec![compiled])?;
+        Ok(self.plugins.last().unwrap())
+    }
+
+    /// Instantiates the plugins in the order of their dependencies, which can be on the plugins already loaded or on each other.
+    /// Each dependency gets a single provider: the plugin itself if it implements the API, or else a plugin already loaded,
+    /// or else one of the new ones. If any dependency can't be resolved none of them is loaded
+    pub fn add_all(&mut self, plugins: Vec<CompiledPlugin>) -> Result<(), LoadError> {
+        for (i, plugin) in plugins.iter().enumerate() {
+            let name = plugin.plugin_name();
+            if self.get(name).is_some() || plugins[..i].iter().any(|p| p.plugin_name() == name) {
+                return Err(LoadError::AlreadyLoaded(name.to_string()));
+            }
+        }
+
+        let loaded: Vec<&PluginMetadata> = self.plugins.iter().map(|p| p.compiled.metadata()).collect();
+        let batch: Vec<&PluginMetadata> = plugins.iter().map(CompiledPlugin::metadata).collect();
+        let resolution = resolve(&loaded, &batch)?;
+
+        // The dependencies of each plugin are loaded before it, so it can call them
+        let mut plugins: Vec<Option<CompiledPlugin>> = plugins.into_iter().map(Some).collect();
+        let mut loaded_at = vec![0; plugins.len()];
+        for i in resolution.order {
+            let compiled = plugins[i].take().unwrap();
+            let dependencies = compiled
+                .metadata()
+                .dependencies
+                .iter()
+                .zip(&resolution.providers[i])
+                .filter_map(|(dependency, provider)| {
+                    let provider = match *provider {
+                        // The plugin calls its own implementation directly, a handle to it would deadlock on its instance
+                        Provider::Itself => return None,
+                        Provider::Loaded(j) => &self.plugins[j],
+                        Provider::Batch(j) => &self.plugins[loaded_at[j]],
+                    };
+                    Some(DependencyInstance {
+                        dependency: dependency.clone(),
+                        compiled: provider.compiled.clone(),
+                        instance: provider.instance.clone(),
+                    })
+                })
+                .collect();
+            let instance = compiled.instantiate_with_dependencies(dependencies);
+            loaded_at[i] = self.plugins.len();
+            self.plugins.push(ManagedPlugin { compiled, instance });
+        }
+        Ok(())
+    }
+
+    pub fn plugins(&self) -> &[ManagedPlugin] {
+        &self.plugins
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ManagedPlugin> {
+        self.plugins.iter().find(|p| p.name() == name)
+    }
+
+    /// The APIs provided by the plugin, if it is loaded
+    pub fn apis(&self, name: &str) -> Option<&[PluginApi]> {
+        self.get(name).map(ManagedPlugin::apis)
+    }
+
+    /// The plugins which provide the API
+    pub fn providers<'a>(&'a self, api_name: &'a str, api_version: &'a str) -> impl Iterator<Item = &'a ManagedPlugin> {
+        self.plugins.iter().filter(move |p| p.provides(api_name, api_version))
+    }
+
+    /// A snapshot of the statistics of the calls made to each plugin
+    pub fn stats(&self) -> Vec<PluginStats> {
+        self.plugins.iter().map(|p| p.compiled.stats()).collect()
+    }
+
+    /// A loader of the API for each plugin providing it
+    pub fn loaders<L: ApiLoader>(&self) -> Vec<L> {
+        self.providers(L::API_NAME, L::API_VERSION)
+            .map(|p| L::from_instance(&p.compiled, &p.instance))
+            .collect()
+    }
+}
+
+/// Whether the plugin is the one the dependency is on, or implements the API it is on, with a matching version
+fn satisfies(metadata: &PluginMetadata, dependency: &Dependency, req: &VersionReq) -> bool {
+    let matches = |version: &str| matches!(Version::parse(version), Ok(v) if req.matches(&v));
+    match dependency.kind {
+        DependencyKind::Plugin => metadata.info.name == dependency.name && matches(&metadata.info.version),
+        DependencyKind::Api => metadata
+            .apis
+            .iter()
+            .any(|api| api.name == dependency.name && matches(&api.version)),
+    }
+}
+
+/// The plugin satisfying a dependency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Provider {
+    /// The plugin implements the API it depends on
+    Itself,
+    /// One of the plugins already loaded, by index
+    Loaded(usize),
+    /// One of the plugins being loaded, by index
+    Batch(usize),
+}
+
+/// The order to load a batch of plugins in, and the provider of each of their dependencies
+#[derive(Debug, PartialEq, Eq)]
+struct Resolution {
+    order: Vec<usize>,
+    /// The provider of each dependency of each plugin, in the order of the dependencies
+    providers: Vec<Vec<Provider>>,
+}
+
+/// Picks one provider for each dependency of the plugins being loaded: the plugin itself, or else the first plugin already loaded,
+/// or else the first plugin of the batch satisfying it. The plugins are then ordered after the providers in the batch
+fn resolve(loaded: &[&PluginMetadata], batch: &[&PluginMetadata]) -> Result<Resolution, LoadError> {
+    let mut providers = Vec::with_capacity(batch.len());
+    for (i, plugin) in batch.iter().enumerate() {
+        let mut plugin_providers = Vec::with_capacity(plugin.dependencies.len());
+        for dependency in &plugin.dependencies {
+            let req = VersionReq::parse(&dependency.version_req).map_err(|_| LoadError::InvalidVersionRequirement {
+                plugin: plugin.info.name.clone(),
+                dependency: dependency.clone(),
+            })?;
+            let provider = if satisfies(plugin, dependency, &req) {
+                Provider::Itself
+            } else if let Some(j) = loaded.iter().position(|p| satisfies(p, dependency, &req)) {
+                Provider::Loaded(j)
+            } else if let Some(j) = (0..batch.len()).find(|&j| j != i && satisfies(batch[j], dependency, &req)) {
+                Provider::Batch(j)
+            } else {
+                return Err(LoadError::MissingDependency {
+                    plugin: plugin.info.name.clone(),
+                    dependency: dependency.clone(),
+                });
+            };
+            plugin_providers.push(provider);
+        }
+        providers.push(plugin_providers);
+    }
+
+    // The plugins each one has to be loaded after
+    let edges: Vec<Vec<usize>> = providers
+        .iter()
+        .map(|p| {
+            p.iter()
+                .filter_map(|provider| match provider {
+                    Provider::Batch(j) => Some(*j),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+    let mut order = Vec::with_capacity(batch.len());
+    let mut states = vec![VisitState::Unvisited; batch.len()];
+    for i in 0..batch.len() {
+        visit(i, &edges, &mut states, &mut Vec::new(), &mut order)
+            .map_err(|cycle| LoadError::DependencyCycle(cycle.into_iter().map(|j| batch[j].info.name.clone()).collect()))?;
+    }
+    Ok(Resolution { order, providers })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum VisitState {
+    Unvisited,
+    Visiting,
+    Visited,
+}
+
+/// Depth first search adding the plugins to `order` after their dependencies.
+/// Returns the cycle (starting and ending with the same plugin) if there is one
+fn visit(
+    i: usize,
+    edges: &[Vec<usize>],
+    states: &mut [VisitState],
+    stack: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+) -> Result<(), Vec<usize>> {
+    match states[i] {
+        VisitState::Visited => return Ok(()),
+        VisitState::Visiting => {
+            let start = stack.iter().position(|&j| j == i).unwrap();
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(i);
+            return Err(cycle);
+        }
+        VisitState::Unvisited => (),
+    }
+    states[i] = VisitState::Visiting;
+    stack.push(i);
+    for &j in &edges[i] {
+        visit(j, edges, states, stack, order)?;
+    }
+    stack.pop();
+    states[i] = VisitState::Visited;
+    order.push(i);
+    Ok(())
+}
+
+/// A plugin couldn't be loaded by the [`PluginManager`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// A plugin with the same name is already loaded
+    AlreadyLoaded(String),
+    /// No plugin satisfies one of the plugin's dependencies
+    MissingDependency { plugin: String, dependency: Dependency },
+    /// The plugins depend on each other, the first one is repeated at the end
+    DependencyCycle(Vec<String>),
+    /// The version requirement of one of the plugin's dependencies isn't valid semver
+    InvalidVersionRequirement { plugin: String, dependency: Dependency },
+    /// The plugin module isn't trusted by the engine's trust policy, it wasn't compiled
+    Untrusted(Untrusted),
+    /// The module couldn't be compiled, or its metadata couldn't be read or is for another ABI version
+    InvalidPlugin(String),
+    /// The assets of the plugin package couldn't be extracted
+    Assets(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::AlreadyLoaded(name) => write!(f, "a plugin named {} is already loaded", name),
+            LoadError::MissingDependency { plugin, dependency } => {
+                write!(f, "the plugin {} depends on the {}, which isn't loaded", plugin, dependency)
+            }
+            LoadError::DependencyCycle(cycle) => write!(f, "the plugins depend on each other: {}", cycle.join(" -> ")),
+            LoadError::InvalidVersionRequirement { plugin, dependency } => {
+                write!(f, "the plugin {} has an invalid version requirement on the {}", plugin, dependency)
+            }
+            LoadError::Untrusted(e) => write!(f, "{}", e),
+            LoadError::InvalidPlugin(e) => write!(f, "the plugin isn't valid: {}", e),
+            LoadError::Assets(e) => write!(f, "couldn't extract the plugin assets: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl Default for PluginManager {
+    fn default() -> Self {
+        Self::new(Engine::default())
+    }
+}
+
+/// A plugin loaded by a [`PluginManager`], with the instance its APIs are called on
+pub struct ManagedPlugin {
+    compiled: CompiledPlugin,
+    instance: PluginInstance,
+}
+
+impl ManagedPlugin {
+    pub fn name(&self) -> &str {
+        self.compiled.plugin_name()
+    }
+
+    /// The APIs the plugin provides
+    pub fn apis(&self) -> &[PluginApi] {
+        self.compiled.apis()
+    }
+
+    pub fn provides(&self, api_name: &str, api_version: &str) -> bool {
+        self.compiled.provides(api_name, api_version)
+    }
+
+    pub fn compiled(&self) -> &CompiledPlugin {
+        &self.compiled
+    }
+
+    /// The manifest of the package the plugin was loaded from, if it was
+    pub fn package_manifest(&self) -> Option<&PackageManifest> {
+        self.compiled.package_manifest()
+    }
+
+    /// The instance the loaders of all the plugin's APIs are bound to
+    pub fn instance(&self) -> &PluginInstance {
+        &self.instance
+    }
+
+    /// The calls and instructions run by each wasm function of the plugin, if the manager's engine has profiling
+    pub fn profile(&self) -> Option<Profile> {
+        self.compiled.profile(&self.instance)
+    }
+
+    /// A loader of the API bound to the plugin instance, if the plugin provides it
+    pub fn loader<L: ApiLoader>(&self) -> Option<L> {
+        if self.provides(L::API_NAME, L::API_VERSION) {
+            Some(L::from_instance(&self.compiled, &self.instance))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::PluginInfo;
+
+    fn plugin(name: &str, apis: &[&str], dependencies: &[(DependencyKind, &str)]) -> PluginMetadata {
+        PluginMetadata {
+            info: PluginInfo {
+                name: name.to_string(),
+                version: "1.0.0".to_string(),
+                ..PluginInfo::default()
+            },
+            apis: apis
+                .iter()
+                .map(|api| PluginApi { name: api.to_string(), version: "0.1.0".to_string(), functions: Vec::new() })
+                .collect(),
+            dependencies: dependencies
+                .iter()
+                .map(|&(kind, name)| Dependency { kind, name: name.to_string(), version_req: "*".to_string() })
+                .collect(),
+            ..PluginMetadata::default()
+        }
+    }
+
+    #[test]
+    fn orders_the_plugins_after_their_dependencies() {
+        let app = plugin("app", &[], &[(DependencyKind::Plugin, "codec"), (DependencyKind::Api, "STORAGE")]);
+        let codec = plugin("codec", &["CODEC"], &[(DependencyKind::Api, "STORAGE")]);
+        let storage = plugin("storage", &["STORAGE"], &[]);
+        let resolution = resolve(&[], &[&app, &codec, &storage]).unwrap();
+        assert_eq!(resolution.order, [2, 1, 0]);
+        assert_eq!(resolution.providers, [vec![Provider::Batch(1), Provider::Batch(2)], vec![Provider::Batch(2)], vec![]]);
+    }
+
+    #[test]
+    fn picks_a_single_provider_preferring_the_loaded_plugins() {
+        let app = plugin("app", &[], &[(DependencyKind::Api, "STORAGE")]);
+        let disk = plugin("disk", &["STORAGE"], &[]);
+        let memory = plugin("memory", &["STORAGE"], &[]);
+        let resolution = resolve(&[], &[&app, &disk, &memory]).unwrap();
+        assert_eq!(resolution.providers[0], [Provider::Batch(1)]);
+        let resolution = resolve(&[&memory], &[&app, &disk]).unwrap();
+        assert_eq!(resolution.providers[0], [Provider::Loaded(0)]);
+        assert_eq!(resolution.order, [0, 1]);
+    }
+
+    #[test]
+    fn plugins_providing_the_api_they_depend_on_are_satisfied_by_themselves() {
+        let storage = plugin("storage", &["STORAGE"], &[(DependencyKind::Api, "STORAGE")]);
+        let other = plugin("other", &["STORAGE"], &[]);
+        let resolution = resolve(&[&other], &[&storage]).unwrap();
+        assert_eq!(resolution.providers, [vec![Provider::Itself]]);
+        assert_eq!(resolution.order, [0]);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let a = plugin("a", &["A"], &[(DependencyKind::Api, "B")]);
+        let b = plugin("b", &["B"], &[(DependencyKind::Plugin, "a")]);
+        let error = resolve(&[], &[&a, &b]).unwrap_err();
+        assert_eq!(error, LoadError::DependencyCycle(vec!["a".to_string(), "b".to_string(), "a".to_string()]));
+    }
+
+    #[test]
+    fn rejects_missing_dependencies_and_invalid_requirements() {
+        let app = plugin("app", &[], &[(DependencyKind::Api, "STORAGE")]);
+        let error = resolve(&[], &[&app]).unwrap_err();
+        assert!(matches!(error, LoadError::MissingDependency { plugin, .. } if plugin == "app"));
+
+        let mut app = plugin("app", &[], &[(DependencyKind::Plugin, "codec")]);
+        app.dependencies[0].version_req = "not a requirement".to_string();
+        let codec = plugin("codec", &[], &[]);
+        let error = resolve(&[&codec], &[&app]).unwrap_err();
+        assert!(matches!(error, LoadError::InvalidVersionRequirement { plugin, .. } if plugin == "app"));
+
+        // A version which doesn't match is missing too
+        let mut app = plugin("app", &[], &[(DependencyKind::Plugin, "codec")]);
+        app.dependencies[0].version_req = "^2".to_string();
+        let error = resolve(&[&codec], &[&app]).unwrap_err();
+        assert!(matches!(error, LoadError::MissingDependency { .. }));
+    }
+}
@@ -0,0 +1,438 @@
+use std::{collections::HashMap, convert::TryInto, fmt};
+
+use crate::{abi::ABI_VERSION, leb128};
+
+/// The custom section the plugin macros embed the metadata in
+pub(crate) const SECTION: &str = "wasm_plugin_framework";
+
+/// An API implemented by a plugin
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PluginApi {
+    pub name: String,
+    pub version: String,
+    /// The names of the API functions
+    pub functions: Vec<String>,
+}
+
+impl PluginApi {
+    /// The symbol the plugins export the API function with, the same as the one the macros generate
+    pub fn symbol(&self, function: &str) -> String {
+        format!("__wpf__{}__{}__fn_{}", escape(&self.name), escape(&self.version), escape(function))
+    }
+}
+
+/// Escapes a part of the symbols, keeping the ASCII letters and digits and turning any other byte into `_` and its hex value.
+/// The escaped parts never contain `__`, which separates them, so different names and versions can't end up with the same symbols.
+/// The macros escape the symbols they export the same way
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| if b.is_ascii_alphanumeric() { (b as char).to_string() } else { format!("_{:02x}", b) })
+        .collect()
+}
+
+/// Information about a plugin, given to the plugin macros or taken from the plugin crate's manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginInfo {
+    pub name: String,
+    pub version: String,
+    pub authors: Vec<String>,
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub license: Option<String>,
+}
+
+/// The fields of the plugin records, which are merged into its `PluginInfo`
+const INFO_FIELDS: [&str; 6] = ["name", "version", "authors", "description", "homepage", "license"];
+
+impl PluginInfo {
+    fn from_fields(fields: &HashMap<&'static str, (String, bool)>) -> Self {
+        let field = |key: &str| fields.get(key).map(|(value, _)| value.clone()).unwrap_or_default();
+        let optional_field = |key: &str| Some(field(key)).filter(|v| !v.is_empty());
+        PluginInfo {
+            name: field("name"),
+            version: field("version"),
+            authors: field("authors")
+                .lines()
+                .filter(|a| !a.is_empty())
+                .map(str::to_string)
+                .collect(),
+            description: optional_field("description"),
+            homepage: optional_field("homepage"),
+            license: optional_field("license"),
+        }
+    }
+}
+
+/// What a plugin depends on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DependencyKind {
+    /// A plugin with the given name
+    Plugin,
+    /// Any plugin implementing the API with the given name
+    Api,
+}
+
+/// A dependency of a plugin, which has to be loaded before it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Dependency {
+    pub kind: DependencyKind,
+    pub name: String,
+    /// A semver requirement on the plugin or API version, like the ones of cargo dependencies
+    pub version_req: String,
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            DependencyKind::Plugin => write!(f, "plugin {} {}", self.name, self.version_req),
+            DependencyKind::Api => write!(f, "API {} {}", self.name, self.version_req),
+        }
+    }
+}
+
+/// The metadata of a plugin, which can be read from the module bytes without compiling nor instantiating it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginMetadata {
+    /// The version of the framework ABI the plugin was built with
+    pub abi_version: u32,
+    pub info: PluginInfo,
+    /// The APIs the plugin implements
+    pub apis: Vec<PluginApi>,
+    /// The plugins or APIs it depends on
+    pub dependencies: Vec<Dependency>,
+}
+
+impl PluginMetadata {
+    /// Reads the metadata from the custom section of the wasm module
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError> {
+        Self::from_sections(custom_sections(bytes, SECTION)?)
+    }
+
+    pub fn provides(&self, api_name: &str, api_version: &str) -> bool {
+        self.apis.iter().any(|api| api.name == api_name && api.version == api_version)
+    }
+
+    /// Checks that the plugin was built with the same version of the framework ABI as the host
+    pub fn check_abi_version(&self) -> Result<(), Incompatibility> {
+        if self.abi_version == ABI_VERSION {
+            Ok(())
+        } else {
+            Err(Incompatibility::AbiVersion {
+                plugin: self.abi_version,
+                host: ABI_VERSION,
+            })
+        }
+    }
+
+    /// Checks that the plugin implements the API with all the given functions, like the generated loaders do before binding to a plugin
+    pub fn check_api(&self, api_name: &str, api_version: &str, functions: &[&str]) -> Result<(), Incompatibility> {
+        self.check_abi_version()?;
+        let api = self
+            .apis
+            .iter()
+            .find(|api| api.name == api_name && api.version == api_version)
+            .ok_or_else(|| Incompatibility::MissingApi {
+                name: api_name.to_string(),
+                version: api_version.to_string(),
+                provided: self.apis.clone(),
+            })?;
+        let missing: Vec<String> = functions
+            .iter()
+            .filter(|f| !api.functions.iter().any(|g| g == *f))
+            .map(|f| f.to_string())
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Incompatibility::MissingFunctions {
+                api: api_name.to_string(),
+                functions: missing,
+            })
+        }
+    }
+
+    /// Reads the metadata from the contents of the custom sections, there can be many as some tools don't merge them
+    pub(crate) fn from_sections<I, S>(sections: I) -> Result<Self, MetadataError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        let mut abi_version = None;
+        let mut info = HashMap::new();
+        let mut apis = Vec::new();
+        let mut dependencies = Vec::new();
+        for section in sections {
+            let mut section = section.as_ref();
+            while !section.is_empty() {
+                let len = read_u32(&mut section)? as usize;
+                let mut record = take(&mut section, len)?;
+                let mut fields = HashMap::new();
+                while !record.is_empty() {
+                    let key = read_str(&mut record)?;
+                    let value = read_str(&mut record)?;
+                    fields.insert(key, value);
+                }
+                let field = |key: &str| fields.get(key).cloned().unwrap_or_default();
+                match fields.get("kind").map(String::as_str) {
+                    Some("plugin") => {
+                        let record_abi_version = field("abi_version").parse().map_err(|_| MetadataError::Malformed)?;
+                        abi_version.get_or_insert(record_abi_version);
+                        // Each API implementation embeds the plugin record, with the fields given to its macro marked as explicit
+                        // and the others defaulting to the manifest of its crate. The explicit ones win, and can't disagree
+                        let explicit = field("explicit");
+                        let explicit: Vec<&str> = explicit.split(',').collect();
+                        for &key in INFO_FIELDS.iter() {
+                            let value = field(key);
+                            let is_explicit = explicit.contains(&key);
+                            match info.get(key) {
+                                Some((first, true)) if is_explicit && *first != value => {
+                                    return Err(MetadataError::ConflictingInfo(key))
+                                }
+                                Some((_, false)) if is_explicit => (),
+                                Some(_) => continue,
+                                None if value.is_empty() && !is_explicit => continue,
+                                None => (),
+                            }
+                            info.insert(key, (value, is_explicit));
+                        }
+                    }
+                    Some("api") => apis.push(PluginApi {
+                        name: field("name"),
+                        version: field("version"),
+                        functions: field("functions")
+                            .split(',')
+                            .filter(|f| !f.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                    }),
+                    Some("dependency") => {
+                        let dependency = Dependency {
+                            kind: match field("on").as_str() {
+                                "plugin" => DependencyKind::Plugin,
+                                "api" => DependencyKind::Api,
+                                _ => return Err(MetadataError::Malformed),
+                            },
+                            name: field("name"),
+                            version_req: field("version"),
+                        };
+                        // Each API implementation may declare the same dependency
+                        if !dependencies.contains(&dependency) {
+                            dependencies.push(dependency);
+                        }
+                    }
+                    // Records added by later versions of the framework
+                    _ => (),
+                }
+            }
+        }
+        Ok(PluginMetadata {
+            abi_version: abi_version.ok_or(MetadataError::Missing)?,
+            info: PluginInfo::from_fields(&info),
+            apis,
+            dependencies,
+        })
+    }
+}
+
+/// The metadata of a plugin couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataError {
+    /// The bytes aren't a wasm module
+    NotWasm,
+    /// The module doesn't have the metadata section, it wasn't built with the plugin macros or it was stripped
+    Missing,
+    /// The metadata section couldn't be decoded
+    Malformed,
+    /// The API implementations of the plugin were given different values for this field of its info
+    ConflictingInfo(&'static str),
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::NotWasm => write!(f, "not a wasm module"),
+            MetadataError::Missing => write!(f, "the module doesn't have the plugin metadata section"),
+            MetadataError::Malformed => write!(f, "the plugin metadata section is malformed"),
+            MetadataError::ConflictingInfo(field) => {
+                write!(f, "the API implementations of the plugin were given different values for its {}", field)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// A plugin can't be used with an API of the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incompatibility {
+    /// The plugin was built with another version of the framework ABI
+    AbiVersion { plugin: u32, host: u32 },
+    /// The plugin doesn't implement the API, only the ones in `provided`
+    MissingApi {
+        name: String,
+        version: String,
+        provided: Vec<PluginApi>,
+    },
+    /// The plugin implements the API, but not all of its functions
+    MissingFunctions { api: String, functions: Vec<String> },
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::AbiVersion { plugin, host } => write!(
+                f,
+                "the plugin was built with an incompatible version of the plugin framework (ABI version {}, expected {})",
+                plugin, host
+            ),
+            Incompatibility::MissingApi { name, version, provided } => {
+                let provided: Vec<String> = provided.iter().map(|api| format!("{} {}", api.name, api.version)).collect();
+                write!(
+                    f,
+                    "the plugin doesn't provide the API {} {}, only [{}]",
+                    name,
+                    version,
+                    provided.join(", ")
+                )
+            }
+            Incompatibility::MissingFunctions { api, functions } => write!(
+                f,
+                "the plugin doesn't implement the functions {} of the API {}",
+                functions.join(", "),
+                api
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Incompatibility {}
+
+/// The names and sizes of all the custom sections of the wasm module, in the order they appear in
+pub fn custom_section_sizes(bytes: &[u8]) -> Result<Vec<(String, usize)>, MetadataError> {
+    Ok(all_custom_sections(bytes)?
+        .into_iter()
+        .map(|(name, section)| (String::from_utf8_lossy(name).into_owned(), section.len()))
+        .collect())
+}
+
+/// The contents of the custom sections with the given name
+fn custom_sections<'a>(bytes: &'a [u8], name: &str) -> Result<Vec<&'a [u8]>, MetadataError> {
+    Ok(all_custom_sections(bytes)?
+        .into_iter()
+        .filter(|(section_name, _)| *section_name == name.as_bytes())
+        .map(|(_, section)| section)
+        .collect())
+}
+
+/// The name and contents of a custom section
+type CustomSection<'a> = (&'a [u8], &'a [u8]);
+
+/// The names and contents of all the custom sections
+fn all_custom_sections(bytes: &[u8]) -> Result<Vec<CustomSection<'_>>, MetadataError> {
+    let mut bytes = match bytes {
+        [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, rest @ ..] => rest,
+        _ => return Err(MetadataError::NotWasm),
+    };
+    let mut sections = Vec::new();
+    while !bytes.is_empty() {
+        let id = take(&mut bytes, 1).map_err(|_| MetadataError::NotWasm)?[0];
+        let len = leb128::read(&mut bytes).ok_or(MetadataError::NotWasm)? as usize;
+        let mut section = take(&mut bytes, len).map_err(|_| MetadataError::NotWasm)?;
+        if id == 0 {
+            let name_len = leb128::read(&mut section).ok_or(MetadataError::NotWasm)? as usize;
+            let section_name = take(&mut section, name_len).map_err(|_| MetadataError::NotWasm)?;
+            sections.push((section_name, section));
+        }
+    }
+    Ok(sections)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], MetadataError> {
+    if bytes.len() < len {
+        return Err(MetadataError::Malformed);
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, MetadataError> {
+    Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+fn read_str(bytes: &mut &[u8]) -> Result<String, MetadataError> {
+    let len = read_u32(bytes)? as usize;
+    String::from_utf8(take(bytes, len)?.to_vec()).map_err(|_| MetadataError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_plugins::record;
+
+    fn api(name: &str, version: &str) -> PluginApi {
+        PluginApi { name: name.to_string(), version: version.to_string(), functions: Vec::new() }
+    }
+
+    #[test]
+    fn symbols_escape_the_api_name_and_version() {
+        assert_eq!(api("API NAME", "0.1.0").symbol("a"), "__wpf__API_20NAME__0_2e1_2e0__fn_a");
+        assert_eq!(api("a_b", "1.0.0-rc.1").symbol("f_g"), "__wpf__a_5fb__1_2e0_2e0_2drc_2e1__fn_f_5fg");
+    }
+
+    #[test]
+    fn symbols_of_different_apis_are_different() {
+        // All of these used to be `__wpf_a_b_1_0_0_fn_f`
+        let symbols = [api("a b", "1.0.0"), api("a_b", "1.0.0"), api("a.b", "1.0.0"), api("a", "b_1.0.0")]
+            .iter()
+            .map(|api| api.symbol("f"))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(symbols.len(), 4);
+        assert_ne!(api("a", "1.0.0").symbol("b__f"), api("a", "1.0.0__b").symbol("f"));
+    }
+
+    /// The plugin record of an API implementation, with the fields given to its macro, the others defaulting to the crate's
+    fn plugin_record(explicit: &[(&str, &str)]) -> Vec<u8> {
+        let mut fields = vec![("name", "crate"), ("version", "0.1.0"), ("authors", "Crate Author"), ("license", "MIT")];
+        fields.retain(|(key, _)| !explicit.iter().any(|(k, _)| k == key));
+        fields.extend_from_slice(explicit);
+        let keys: Vec<&str> = explicit.iter().map(|(key, _)| *key).collect();
+        let keys = keys.join(",");
+        fields.push(("explicit", &keys));
+        record("plugin", &fields)
+    }
+
+    #[test]
+    fn explicit_plugin_info_wins_over_the_defaults_whatever_the_order() {
+        let explicit = plugin_record(&[("name", "plugin"), ("version", "1.0.0"), ("description", "A plugin")]);
+        let defaulted = plugin_record(&[]);
+        for sections in [[&explicit, &defaulted], [&defaulted, &explicit]] {
+            let info = PluginMetadata::from_sections(sections).unwrap().info;
+            assert_eq!(info.name, "plugin");
+            assert_eq!(info.version, "1.0.0");
+            assert_eq!(info.description.as_deref(), Some("A plugin"));
+            assert_eq!(info.authors, ["Crate Author"]);
+            assert_eq!(info.license.as_deref(), Some("MIT"));
+        }
+    }
+
+    #[test]
+    fn conflicting_plugin_info_is_rejected() {
+        let sections = [plugin_record(&[("version", "1.0.0")]), plugin_record(&[("version", "2.0.0")])];
+        assert_eq!(PluginMetadata::from_sections(&sections), Err(MetadataError::ConflictingInfo("version")));
+        // The same value given twice is fine
+        let sections = [plugin_record(&[("version", "1.0.0")]), plugin_record(&[("version", "1.0.0")])];
+        assert_eq!(PluginMetadata::from_sections(&sections).unwrap().info.version, "1.0.0");
+    }
+
+    #[test]
+    fn records_without_explicit_fields_keep_the_first_values() {
+        let sections = [
+            record("plugin", &[("name", "first"), ("version", "0.1.0")]),
+            record("plugin", &[("name", "second"), ("version", "0.2.0"), ("license", "MIT")]),
+        ];
+        let info = PluginMetadata::from_sections(&sections).unwrap().info;
+        assert_eq!((info.name.as_str(), info.version.as_str()), ("first", "0.1.0"));
+        assert_eq!(info.license.as_deref(), Some("MIT"));
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{MetadataError, PluginMetadata};
+
+/// The extension of the plugin packages
+pub const PACKAGE_EXTENSION: &str = "wplugin";
+
+/// The path of the manifest in the package
+const MANIFEST: &str = "manifest.toml";
+
+/// The directory of the assets in the package
+const ASSETS: &str = "assets/";
+
+/// The directory the plugins see their assets in, in their WASI filesystem
+pub const ASSETS_DIR: &str = "/assets";
+
+/// The manifest of a plugin package, readable by the host without compiling the plugin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageManifest {
+    pub name: String,
+    pub version: String,
+    /// The path of the wasm module in the package
+    pub module: String,
+    /// Anything else the host needs to know about the plugin, the framework doesn't use it
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A plugin packaged in a single file with its manifest and assets.
+///
+/// The package is a tar archive with `manifest.toml`, the wasm module and its signature (if it is signed) at its root,
+/// and the assets under `assets/`. The signature and the pinned hashes of a [`TrustPolicy`](crate::TrustPolicy)
+/// cover the whole package (see [`PluginPackage::signed_contents`]), so its assets can't be swapped.
+/// The plugins loaded from a package see the assets as a read-only directory at [`ASSETS_DIR`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginPackage {
+    manifest: PackageManifest,
+    module: Vec<u8>,
+    /// The detached signature of the package contents, stored next to the module in the package
+    signature: Option<Vec<u8>>,
+    /// The contents of each asset, by path relative to the assets directory
+    assets: BTreeMap<String, Vec<u8>>,
+}
+
+impl PluginPackage {
+    /// Packages the module without any asset, taking the manifest name and version from the plugin metadata
+    pub fn new(module: Vec<u8>) -> Result<Self, PackageError> {
+        let info = PluginMetadata::from_bytes(&module)?.info;
+        Ok(Self {
+            manifest: PackageManifest {
+                module: format!("{}.wasm", info.name),
+                name: info.name,
+                version: info.version,
+                metadata: BTreeMap::new(),
+            },
+            module,
+            signature: None,
+            assets: BTreeMap::new(),
+        })
+    }
+
+    /// Sets the detached signature of the package contents (as given by [`PluginPackage::signed_contents`]), as made by `wasm-plugin sign`
+    pub fn with_signature(mut self, signature: Vec<u8>) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+
+    /// Adds a metadata entry to the manifest
+    pub fn with_metadata<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.manifest.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds an asset, at a `/` separated path relative to the assets directory
+    pub fn with_asset<P: Into<String>>(mut self, path: P, contents: Vec<u8>) -> Result<Self, PackageError> {
+        let path = path.into();
+        let normalized = normalize(&path).ok_or(PackageError::InvalidPath(path))?;
+        insert_asset(&mut self.assets, normalized, contents)?;
+        Ok(self)
+    }
+
+    /// Adds all the files under the directory as assets, keeping their paths relative to it
+    pub fn with_assets_dir<P: AsRef<Path>>(mut self, dir: P) -> Result<Self, PackageError> {
+        let dir = dir.as_ref();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(&current)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let relative = path.strip_prefix(dir).unwrap().to_string_lossy().replace('\\', "/");
+                    self = self.with_asset(relative, fs::read(&path)?)?;
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// Reads a package file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PackageError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Reads a package from its bytes, checking that the manifest matches the plugin metadata
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PackageError> {
+        let mut files = BTreeMap::new();
+        for entry in tar::Archive::new(bytes).entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => continue,
+                tar::EntryType::Regular | tar::EntryType::Continuous => (),
+                // Links could point outside of the package once the assets are extracted
+                _ => return Err(PackageError::InvalidPath(path)),
+            }
+            let normalized = normalize(&path).ok_or(PackageError::InvalidPath(path))?;
+            // The size in the header isn't trusted to preallocate, as a crafted one would abort on the allocation
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            files.insert(normalized, contents);
+        }
+
+        let manifest = files.remove(MANIFEST).ok_or(PackageError::MissingManifest)?;
+        let manifest: PackageManifest = toml::from_slice(&manifest).map_err(|e| PackageError::InvalidManifest(e.to_string()))?;
+        let module = files
+            .remove(&manifest.module)
+            .ok_or_else(|| PackageError::MissingModule(manifest.module.clone()))?;
+        match PluginMetadata::from_bytes(&module) {
+            Ok(metadata) => {
+                if metadata.info.name != manifest.name || metadata.info.version != manifest.version {
+                    return Err(PackageError::ManifestMismatch {
+                        manifest: format!("{} {}", manifest.name, manifest.version),
+                        plugin: format!("{} {}", metadata.info.name, metadata.info.version),
+                    });
+                }
+            }
+            // The section may have been stripped by a tool, the loader reads the exported metadata instead
+            Err(MetadataError::Missing) => (),
+            Err(e) => return Err(e.into()),
+        }
+        let signature = files.remove(&signature_path(&manifest.module));
+        let mut assets = BTreeMap::new();
+        for (path, contents) in files {
+            if let Some(path) = path.strip_prefix(ASSETS) {
+                insert_asset(&mut assets, path.to_string(), contents)?;
+            }
+        }
+        Ok(Self {
+            manifest,
+            module,
+            signature,
+            assets,
+        })
+    }
+
+    /// Writes the package as a tar archive. The entries have fixed permissions and times, so the same package always gives the same bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let manifest = toml::to_vec(&self.manifest).expect("Error encoding the package manifest");
+        let mut builder = tar::Builder::new(Vec::new());
+        let files = std::iter::once((MANIFEST.to_string(), &manifest))
+            .chain(std::iter::once((self.manifest.module.clone(), &self.module)))
+            .chain(self.signature.iter().map(|signature| (signature_path(&self.manifest.module), signature)))
+            .chain(self.assets.iter().map(|(path, contents)| (format!("{}{}", ASSETS, path), contents)));
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_entry_type(tar::EntryType::Regular);
+            builder
+                .append_data(&mut header, path, contents.as_slice())
+                .expect("Error writing the package");
+        }
+        builder.into_inner().expect("Error writing the package")
+    }
+
+    /// Writes the package to a file
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn manifest(&self) -> &PackageManifest {
+        &self.manifest
+    }
+
+    /// The bytes of the wasm module
+    pub fn module(&self) -> &[u8] {
+        &self.module
+    }
+
+    pub fn signature(&self) -> Option<&[u8]> {
+        self.signature.as_deref()
+    }
+
+    /// The bytes the signature of the package is made on, and whose hash is pinned to trust it: the manifest, the module
+    /// and every asset with its path, each prefixed with its length so that they can't be shifted into each other
+    pub fn signed_contents(&self) -> Vec<u8> {
+        let manifest = toml::to_vec(&self.manifest).expect("Error encoding the package manifest");
+        let mut contents = Vec::new();
+        let mut add = |part: &[u8]| {
+            contents.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            contents.extend_from_slice(part);
+        };
+        add(&manifest);
+        add(&self.module);
+        for (path, asset) in &self.assets {
+            add(path.as_bytes());
+            add(asset);
+        }
+        contents
+    }
+
+    /// The paths (relative to the assets directory) and contents of the assets
+    pub fn assets(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.assets.iter().map(|(path, contents)| (path.as_str(), contents.as_slice()))
+    }
+
+    pub fn asset(&self, path: &str) -> Option<&[u8]> {
+        self.assets.get(path).map(Vec::as_slice)
+    }
+
+    /// Writes the assets to a new directory private to the user in the system temporary directory,
+    /// which the plugin instances get mapped read-only at [`ASSETS_DIR`].
+    /// The directory isn't named after the untrusted manifest, and it is removed once the returned value is dropped
+    pub(crate) fn extract_assets(&self) -> io::Result<Option<ExtractedAssets>> {
+        if self.assets.is_empty() {
+            return Ok(None);
+        }
+        let root = assets_root()?;
+        remove_abandoned(&root);
+        let dir = loop {
+            let dir = root.join(format!("{}-{}", std::process::id(), NEXT_EXTRACTION.fetch_add(1, Ordering::Relaxed)));
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                // Left by a process which had the same id
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => remove_extracted(&dir)?,
+                Err(e) => return Err(e),
+            }
+        };
+        // Removed on drop if the extraction fails
+        let extracted = ExtractedAssets(dir);
+        for (path, contents) in &self.assets {
+            let file = extracted.0.join(path);
+            fs::create_dir_all(file.parent().unwrap())?;
+            fs::write(file, contents)?;
+        }
+        // The WASI directory rights don't stop the plugins from creating files, the host permissions do
+        set_readonly(&extracted.0)?;
+        Ok(Some(extracted))
+    }
+}
+
+/// Numbers the directories the assets are extracted to by this process
+static NEXT_EXTRACTION: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory the assets of a package were extracted to, shared by the compiled plugin and its instances, and removed when dropped
+#[derive(Debug)]
+pub(crate) struct ExtractedAssets(PathBuf);
+
+impl ExtractedAssets {
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ExtractedAssets {
+    fn drop(&mut self) {
+        if let Err(e) = remove_extracted(&self.0) {
+            tracing::warn!(dir = %self.0.display(), error = %e, "couldn't remove the extracted plugin assets");
+        }
+    }
+}
+
+/// Removes a directory of extracted assets, which is read-only
+fn remove_extracted(dir: &Path) -> io::Result<()> {
+    set_writable(dir)?;
+    fs::remove_dir_all(dir)
+}
+
+/// Removes the directories of extracted assets left by the processes which are gone, eg. because they crashed.
+/// They are named `<process id>-<number>`
+#[cfg(unix)]
+fn remove_abandoned(root: &Path) {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let pid = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.split('-').next()?.parse::<libc::pid_t>().ok());
+        let pid = match pid {
+            Some(pid) if pid as u32 != std::process::id() => pid,
+            _ => continue,
+        };
+        // The signal 0 only checks whether the process exists
+        let gone = unsafe { libc::kill(pid, 0) } == -1 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH);
+        if gone {
+            if let Err(e) = remove_extracted(&entry.path()) {
+                tracing::warn!(dir = %entry.path().display(), error = %e, "couldn't remove abandoned plugin assets");
+            }
+        }
+    }
+}
+
+/// The processes can't be checked portably on the other systems, their directories are left in the temporary directory
+#[cfg(not(unix))]
+fn remove_abandoned(_root: &Path) {}
+
+/// Adds an asset, unless a file would have to be a directory too: `a` and `a/b` can't both be assets
+fn insert_asset(assets: &mut BTreeMap<String, Vec<u8>>, path: String, contents: Vec<u8>) -> Result<(), PackageError> {
+    let parent = path.match_indices('/').map(|(i, _)| &path[..i]).find(|parent| assets.contains_key(*parent));
+    let dir = format!("{}/", path);
+    let child = assets.range(dir.clone()..).next().map(|(child, _)| child).filter(|child| child.starts_with(&dir));
+    if let Some(other) = parent.or_else(|| child.map(String::as_str)) {
+        return Err(PackageError::ConflictingPaths(other.to_string(), path));
+    }
+    assets.insert(path, contents);
+    Ok(())
+}
+
+/// The path of the module signature in the package
+fn signature_path(module: &str) -> String {
+    format!("{}.sig", module)
+}
+
+/// The directory the assets are extracted to, which only the current user can write to,
+/// so that another user can't plant the assets of a package before it is loaded
+#[cfg(unix)]
+fn assets_root() -> io::Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    let uid = unsafe { libc::getuid() };
+    let root = env::temp_dir().join(format!("wasm-plugin-framework-{}", uid));
+    match fs::DirBuilder::new().mode(0o700).create(&root) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => (),
+    }
+    // It may have been created by someone else before, or be a link to somewhere else
+    let metadata = fs::symlink_metadata(&root)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} isn't a directory only the current user can access", root.display()),
+        ));
+    }
+    Ok(root)
+}
+
+/// The directory the assets are extracted to, the temporary directory is already private to the user on the other systems
+#[cfg(not(unix))]
+fn assets_root() -> io::Result<PathBuf> {
+    let root = env::temp_dir().join("wasm-plugin-framework");
+    fs::create_dir_all(&root)?;
+    Ok(root)
+}
+
+/// Removes the write permissions of the directory and everything in it
+fn set_readonly(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            set_readonly(&entry?.path())?;
+        }
+    }
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)
+}
+
+/// Gives the write permissions back to the owner of the directory and everything in it
+fn set_writable(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::symlink_metadata(path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(permissions.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)?;
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            set_writable(&entry?.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// The path with `/` separators, if it is relative and stays inside the package
+fn normalize(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// A plugin package couldn't be read
+#[derive(Debug)]
+pub enum PackageError {
+    Io(io::Error),
+    /// The package doesn't have a `manifest.toml`
+    MissingManifest,
+    InvalidManifest(String),
+    /// The module the manifest points to isn't in the package
+    MissingModule(String),
+    /// A path in the package is absolute, goes out of the package, or isn't a regular file
+    InvalidPath(String),
+    /// An asset is at the path of the directory of another one
+    ConflictingPaths(String, String),
+    /// The plugin metadata couldn't be read from the module
+    Metadata(MetadataError),
+    /// The manifest is for another plugin (or version) than the module
+    ManifestMismatch { manifest: String, plugin: String },
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackageError::Io(e) => write!(f, "couldn't read the package: {}", e),
+            PackageError::MissingManifest => write!(f, "the package doesn't have a {}", MANIFEST),
+            PackageError::InvalidManifest(e) => write!(f, "the package manifest is invalid: {}", e),
+            PackageError::MissingModule(path) => write!(f, "the package doesn't have the module {}", path),
+            PackageError::InvalidPath(path) => write!(f, "the package has an invalid entry {}", path),
+            PackageError::ConflictingPaths(a, b) => write!(f, "the package assets {} and {} can't be both a file and a directory", a, b),
+            PackageError::Metadata(e) => write!(f, "{}", e),
+            PackageError::ManifestMismatch { manifest, plugin } => {
+                write!(f, "the package manifest is for the plugin {}, but the module is {}", manifest, plugin)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PackageError {}
+
+impl From<io::Error> for PackageError {
+    fn from(e: io::Error) -> Self {
+        PackageError::Io(e)
+    }
+}
+
+impl From<MetadataError> for PackageError {
+    fn from(e: MetadataError) -> Self {
+        PackageError::Metadata(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_plugins::{plugin, plugin_record};
+
+    fn package() -> PluginPackage {
+        PluginPackage::new(plugin("", &[plugin_record("packaged", "1.0.0")])).unwrap()
+    }
+
+    /// A tar archive with these entries, which `to_bytes` wouldn't write
+    fn archive(entries: &[(&str, tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_entry_type(*entry_type);
+            builder.append_data(&mut header, path, *contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let package = package()
+            .with_signature(vec![1, 2, 3])
+            .with_metadata("key", "value")
+            .with_asset("./data/a.txt", b"a".to_vec())
+            .unwrap();
+        assert_eq!(package.manifest().module, "packaged.wasm");
+        assert_eq!(package.asset("data/a.txt"), Some(&b"a"[..]));
+
+        let bytes = package.to_bytes();
+        assert_eq!(bytes, package.to_bytes());
+        assert_eq!(PluginPackage::from_bytes(&bytes).unwrap(), package);
+    }
+
+    #[test]
+    fn normalizes_the_paths_inside_the_package() {
+        assert_eq!(normalize("a/./b").as_deref(), Some("a/b"));
+        assert_eq!(normalize("./a/b/").as_deref(), Some("a/b"));
+        for path in ["", ".", "/a", "../a", "a/../b"].iter() {
+            assert_eq!(normalize(path), None, "{}", path);
+        }
+        assert!(matches!(package().with_asset("../a", Vec::new()), Err(PackageError::InvalidPath(_))));
+    }
+
+    #[test]
+    fn rejects_links() {
+        let package = package();
+        let manifest = toml::to_vec(package.manifest()).unwrap();
+        let mut entries = vec![
+            (MANIFEST, tar::EntryType::Regular, manifest.as_slice()),
+            ("packaged.wasm", tar::EntryType::Regular, package.module()),
+        ];
+        assert_eq!(PluginPackage::from_bytes(&archive(&entries)).unwrap(), package);
+        entries.push(("assets/link", tar::EntryType::Symlink, b""));
+        assert!(matches!(PluginPackage::from_bytes(&archive(&entries)), Err(PackageError::InvalidPath(_))));
+    }
+
+    #[test]
+    fn checks_the_manifest() {
+        assert!(matches!(PluginPackage::from_bytes(&archive(&[])), Err(PackageError::MissingManifest)));
+
+        let mut package = package();
+        package.manifest.version = "2.0.0".to_string();
+        assert!(matches!(PluginPackage::from_bytes(&package.to_bytes()), Err(PackageError::ManifestMismatch { .. })));
+
+        package.manifest.module = "other.wasm".to_string();
+        let manifest = toml::to_vec(&package.manifest).unwrap();
+        let bytes = archive(&[(MANIFEST, tar::EntryType::Regular, &manifest)]);
+        assert!(matches!(PluginPackage::from_bytes(&bytes), Err(PackageError::MissingModule(_))));
+    }
+
+    #[test]
+    fn the_signature_covers_the_whole_package() {
+        use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+
+        let secret = SecretKey::from_bytes(&[1; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let keypair = Keypair { secret, public };
+        let policy = crate::TrustPolicy::new().with_public_key(&hex::encode(public.as_bytes())).unwrap();
+        let verify = |package: &PluginPackage| policy.verify(&package.signed_contents(), package.signature());
+
+        let package = package().with_asset("a", b"a".to_vec()).unwrap();
+        let signature = keypair.sign(&package.signed_contents()).to_bytes().to_vec();
+        let package = PluginPackage::from_bytes(&package.with_signature(signature).to_bytes()).unwrap();
+        assert_eq!(verify(&package), Ok(()));
+
+        let mut swapped = package.clone();
+        swapped.assets.insert("a".to_string(), b"b".to_vec());
+        assert!(verify(&swapped).is_err());
+        // The asset paths are part of the signed contents too
+        let mut moved = package.clone();
+        moved.assets = std::iter::once(("b".to_string(), b"a".to_vec())).collect();
+        assert!(verify(&moved).is_err());
+        assert!(verify(&package.clone().with_metadata("key", "value")).is_err());
+        assert!(verify(&package.with_asset("b", Vec::new()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejects_assets_which_would_be_files_and_directories() {
+        let conflicting = |first: &str, second: &str| package().with_asset(first, Vec::new()).unwrap().with_asset(second, Vec::new());
+        assert!(matches!(conflicting("a", "a/b/c"), Err(PackageError::ConflictingPaths(_, _))));
+        assert!(matches!(conflicting("a/b/c", "a/b"), Err(PackageError::ConflictingPaths(_, _))));
+        assert!(conflicting("a-b", "a/b").is_ok());
+        assert!(conflicting("a/b", "a/c").is_ok());
+
+        let package = package();
+        let manifest = toml::to_vec(package.manifest()).unwrap();
+        let bytes = archive(&[
+            (MANIFEST, tar::EntryType::Regular, manifest.as_slice()),
+            ("packaged.wasm", tar::EntryType::Regular, package.module()),
+            ("assets/a", tar::EntryType::Regular, b""),
+            ("assets/a/b", tar::EntryType::Regular, b""),
+        ]);
+        assert!(matches!(PluginPackage::from_bytes(&bytes), Err(PackageError::ConflictingPaths(_, _))));
+    }
+
+    #[test]
+    fn extracts_the_assets_in_a_private_directory_removed_when_dropped() {
+        assert!(package().extract_assets().unwrap().is_none());
+
+        let mut package = package().with_asset("data/a.txt", b"a".to_vec()).unwrap();
+        // The manifest name doesn't end up in the path
+        package.manifest.name = "../../escaped".to_string();
+        let extracted = package.extract_assets().unwrap().unwrap();
+        let dir = extracted.path().to_path_buf();
+        let root = assets_root().unwrap();
+        assert_eq!(dir.parent(), Some(root.as_path()));
+        assert_eq!(fs::read(dir.join("data/a.txt")).unwrap(), b"a");
+        assert!(fs::metadata(dir.join("data/a.txt")).unwrap().permissions().readonly());
+        assert_ne!(package.extract_assets().unwrap().unwrap().path(), dir);
+        drop(extracted);
+        assert!(!dir.exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&root).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn removes_the_assets_extracted_by_processes_which_are_gone() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let root = assets_root().unwrap();
+        let abandoned = root.join(format!("{}-0", child.id()));
+        fs::create_dir_all(abandoned.join("data")).unwrap();
+        fs::write(abandoned.join("data/a.txt"), b"a").unwrap();
+        set_readonly(&abandoned).unwrap();
+
+        let _extracted = package().with_asset("a", Vec::new()).unwrap().extract_assets().unwrap();
+        assert!(!abandoned.exists());
+    }
+}
@@ -0,0 +1,115 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use wasmer::{Memory, RuntimeError};
+
+use crate::{
+    abi::{self, PluginLoader},
+    host_functions::HostCallEnv,
+    loader::{CompiledPlugin, InstanceLoader, PluginInstance},
+    metadata::{Dependency, DependencyKind},
+};
+
+/// A plugin another plugin depends on, which it can call through the host
+#[derive(Clone)]
+pub(crate) struct DependencyInstance {
+    pub(crate) dependency: Dependency,
+    pub(crate) compiled: CompiledPlugin,
+    pub(crate) instance: PluginInstance,
+}
+
+/// Reads a string the plugin passed, None if it isn't inside its memory
+fn read_str(memory: &Memory, ptr: u32, len: u32) -> Option<String> {
+    abi::read_bytes(memory, ptr, len).map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Imported by the plugins to get a handle to one of their dependencies, by plugin or API name.
+/// Returns -1 if it isn't one, and -2 if the name isn't inside the plugin memory
+pub(crate) fn plugin_get(env: &HostCallEnv, by_api: u32, name_ptr: u32, name_len: u32) -> i32 {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let name = match read_str(env.memory(), name_ptr, name_len) {
+            Some(name) => name,
+            None => return -2,
+        };
+        env.dependencies
+            .iter()
+            .position(|d| {
+                if by_api != 0 {
+                    d.dependency.kind == DependencyKind::Api && d.dependency.name == name
+                } else {
+                    d.compiled.plugin_name() == name
+                }
+            })
+            .map_or(-1, |i| i as i32)
+    }))
+    .unwrap_or(-2)
+}
+
+/// Imported by the plugins to call an API function of one of their dependencies.
+/// The arguments are copied from the caller's memory into the dependency's, and the result the other way around
+pub(crate) fn plugin_call(
+    env: &HostCallEnv,
+    handle: u32,
+    function_ptr: u32,
+    function_len: u32,
+    args_ptr: u32,
+) -> Result<u32, RuntimeError> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let function = read_str(env.memory(), function_ptr, function_len)
+            .ok_or_else(|| RuntimeError::new("The plugin called a dependency with a function name outside of its memory"))?;
+        let args: Vec<Vec<u8>> = abi::from_bytes(&abi::from_abi_bytes(env, args_ptr));
+        let target = env
+            .dependencies
+            .get(handle as usize)
+            .ok_or_else(|| RuntimeError::new("The plugin called a dependency with an invalid handle"))?;
+        // A dependency on an API only gives access to that API
+        let api = target
+            .compiled
+            .apis()
+            .iter()
+            .filter(|api| target.dependency.kind == DependencyKind::Plugin || api.name == target.dependency.name)
+            .find(|api| api.functions.contains(&function))
+            .ok_or_else(|| {
+                RuntimeError::new(format!(
+                    "The plugin called an unknown function of its dependency {}: {}",
+                    target.compiled.plugin_name(),
+                    function
+                ))
+            })?;
+
+        let function = target
+            .instance
+            .exports
+            .get_function(&api.symbol(&function))
+            .map_err(|e| RuntimeError::new(e.to_string()))?;
+        // The loaders of the dependency may be calling it from other threads
+        let guard = target.instance.lock();
+        let result = abi::call_encoded(&InstanceLoader(&target.instance), function, &args)?;
+        drop(guard);
+        Ok(abi::into_abi_bytes(env, &result))
+    }))
+    .unwrap_or_else(|_| Err(RuntimeError::new("A call to a dependency of the plugin panicked")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        loader::{CompiledPlugin, Engine},
+        test_plugins::{api_record, plugin_importing, plugin_record},
+    };
+
+    #[test]
+    fn plugin_get_rejects_names_outside_of_the_memory() {
+        let module = plugin_importing(
+            r#"(import "wasm_plugin_framework" "plugin_get" (func $plugin_get (param i32 i32 i32) (result i32)))"#,
+            r#"(func (export "get") (param i32 i32 i32) (result i32)
+                (call $plugin_get (local.get 0) (local.get 1) (local.get 2)))"#,
+            &[plugin_record("caller", "0.1.0"), api_record("caller", "0.1.0", &[])],
+        );
+        let instance = CompiledPlugin::new(&Engine::new(), &module).instantiate();
+        let get = instance.exports.get_function("get").unwrap();
+        let get = |ptr: u32, len: u32| get.call(&[0.into(), (ptr as i32).into(), (len as i32).into()]).unwrap()[0].unwrap_i32();
+        assert_eq!(get(0, 4), -1);
+        assert_eq!(get(65_530, 10), -2);
+        assert_eq!(get(u32::MAX - 1, 4), -2);
+    }
+}
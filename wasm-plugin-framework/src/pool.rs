@@ -0,0 +1,143 @@
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+/// A pool of instances of the same plugin, so that calls from many threads don't have to wait on a single instance.
+/// Each call takes an instance out of the pool, and gives it back when it's done.
+pub struct PluginPool<L> {
+    instances: Mutex<Vec<L>>,
+    returned: Condvar,
+    size: usize,
+}
+
+impl<L> PluginPool<L> {
+    /// Panics if there are no instances, as getting one would wait forever
+    pub fn new(instances: Vec<L>) -> Self {
+        assert!(!instances.is_empty(), "A plugin pool needs at least one instance");
+        Self {
+            size: instances.len(),
+            instances: Mutex::new(instances),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// The total number of instances, including the ones in use
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of instances not in use
+    pub fn available(&self) -> usize {
+        self.instances.lock().unwrap().len()
+    }
+
+    /// Takes an instance, blocking until one is available
+    pub fn get(&self) -> PooledPlugin<'_, L> {
+        let mut instances = self.instances.lock().unwrap();
+        loop {
+            if let Some(plugin) = instances.pop() {
+                return self.pooled(plugin);
+            }
+            instances = self.returned.wait(instances).unwrap();
+        }
+    }
+
+    /// Takes an instance, blocking until one is available or the timeout runs out
+    pub fn get_timeout(&self, timeout: Duration) -> Result<PooledPlugin<'_, L>, PoolExhausted> {
+        let instances = self.instances.lock().unwrap();
+        let (mut instances, _) = self
+            .returned
+            .wait_timeout_while(instances, timeout, |i| i.is_empty())
+            .unwrap();
+        instances.pop().map(|p| self.pooled(p)).ok_or(PoolExhausted)
+    }
+
+    /// Takes an instance if there is one available, without blocking
+    pub fn try_get(&self) -> Result<PooledPlugin<'_, L>, PoolExhausted> {
+        let plugin = self.instances.lock().unwrap().pop();
+        plugin.map(|p| self.pooled(p)).ok_or(PoolExhausted)
+    }
+
+    fn pooled(&self, plugin: L) -> PooledPlugin<'_, L> {
+        PooledPlugin {
+            pool: self,
+            plugin: Some(plugin),
+        }
+    }
+}
+
+/// An instance taken from a [`PluginPool`]. It goes back to the pool when dropped.
+pub struct PooledPlugin<'a, L> {
+    pool: &'a PluginPool<L>,
+    plugin: Option<L>,
+}
+
+impl<'a, L> Deref for PooledPlugin<'a, L> {
+    type Target = L;
+
+    fn deref(&self) -> &L {
+        self.plugin.as_ref().unwrap()
+    }
+}
+
+impl<'a, L> Drop for PooledPlugin<'a, L> {
+    fn drop(&mut self) {
+        if let Some(plugin) = self.plugin.take() {
+            self.pool.instances.lock().unwrap().push(plugin);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+/// All the instances of the pool are in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolExhausted;
+
+impl fmt::Display for PoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "all the plugin instances of the pool are in use")
+    }
+}
+
+impl std::error::Error for PoolExhausted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_go_back_to_the_pool() {
+        let pool = PluginPool::new(vec![1, 2]);
+        let a = pool.get();
+        let b = pool.try_get().unwrap();
+        assert_eq!(*a + *b, 3);
+        assert_eq!(pool.available(), 0);
+        assert_eq!(pool.try_get().err(), Some(PoolExhausted));
+        assert_eq!(pool.get_timeout(Duration::from_millis(1)).err(), Some(PoolExhausted));
+        drop(a);
+        assert_eq!(pool.available(), 1);
+        assert!(pool.get_timeout(Duration::from_millis(1)).is_ok());
+        assert_eq!(pool.size(), 2);
+    }
+
+    #[test]
+    fn get_waits_for_an_instance() {
+        let pool = PluginPool::new(vec![()]);
+        let taken = pool.get();
+        std::thread::scope(|s| {
+            s.spawn(|| drop(pool.get()));
+            std::thread::sleep(Duration::from_millis(10));
+            drop(taken);
+        });
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one instance")]
+    fn empty_pools_are_rejected() {
+        PluginPool::<()>::new(Vec::new());
+    }
+}
@@ -0,0 +1,488 @@
+use std::{
+    cmp::Reverse,
+    fmt,
+    io::{self, Write},
+};
+
+use wasmer::{
+    wasmparser::{
+        BinaryReaderError, FunctionBody, ImportSectionEntryType, ImportSectionReader, Name, NameSectionReader, Operator,
+    },
+    Instance, Module, Val,
+};
+
+use crate::leb128;
+
+/// The custom section the instrumented modules have the names of their functions in, in the order of their counters
+const PROFILE_SECTION: &str = "wasm_plugin_framework.profile";
+
+const SECTION_CUSTOM: u8 = 0;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_GLOBAL: u8 = 6;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_CODE: u8 = 10;
+
+/// The export of the counter of calls to the function defined at this position
+fn calls_export(i: usize) -> String {
+    format!("__wpf_profile_calls_{}", i)
+}
+
+/// The export of the counter of instructions run by the function defined at this position
+fn instructions_export(i: usize) -> String {
+    format!("__wpf_profile_instructions_{}", i)
+}
+
+/// The profile of a plugin instance: how many times each of its wasm functions was called, and how many wasm instructions it ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub plugin: String,
+    pub functions: Vec<FunctionProfile>,
+}
+
+/// The profile of a wasm function, including the ones which aren't API functions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// The name from the module's name section, or `function[<index>]` if it was stripped
+    pub name: String,
+    pub calls: u64,
+    /// The wasm instructions run by the function itself, not counting the functions it calls.
+    /// Every instruction counts as one, whatever its cost
+    pub instructions: u64,
+}
+
+impl Profile {
+    /// Reads the counters of an instance of a module compiled by an engine with profiling, or `None` if it wasn't
+    pub(crate) fn read(plugin: &str, module: &Module, instance: &Instance) -> Option<Self> {
+        let names = module.custom_sections(PROFILE_SECTION).next()?;
+        let counter = |export: String| -> Option<u64> {
+            match instance.exports.get_global(&export).ok()?.get() {
+                Val::I64(v) => Some(v as u64),
+                _ => None,
+            }
+        };
+        let functions = String::from_utf8_lossy(&names)
+            .lines()
+            .enumerate()
+            .map(|(i, name)| {
+                Some(FunctionProfile {
+                    name: name.to_string(),
+                    calls: counter(calls_export(i))?,
+                    instructions: counter(instructions_export(i))?,
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            plugin: plugin.to_string(),
+            functions,
+        })
+    }
+
+    /// Sets the counters of the instance back to zero
+    pub(crate) fn reset(module: &Module, instance: &Instance) {
+        let count = module
+            .custom_sections(PROFILE_SECTION)
+            .next()
+            .map_or(0, |names| String::from_utf8_lossy(&names).lines().count());
+        for i in 0..count {
+            for export in [calls_export(i), instructions_export(i)].iter() {
+                if let Ok(global) = instance.exports.get_global(export) {
+                    let _ = global.set(Val::I64(0));
+                }
+            }
+        }
+    }
+
+    /// Adds the counters of another instance of the same plugin, eg. to profile all the instances of a pool.
+    /// Panics if the other profile is of another plugin or module, as its counters would be added to the wrong functions
+    pub fn merge(&mut self, other: &Profile) {
+        let names = |profile: &Profile| profile.functions.iter().map(|f| f.name.clone()).collect::<Vec<_>>();
+        assert!(
+            self.plugin == other.plugin && names(self) == names(other),
+            "Tried to merge the profile of {} with the one of another plugin or module ({})",
+            self.plugin,
+            other.plugin
+        );
+        for (function, other) in self.functions.iter_mut().zip(&other.functions) {
+            function.calls += other.calls;
+            function.instructions += other.instructions;
+        }
+    }
+
+    /// Writes a flat profile in the folded stacks format, which flamegraph tools (eg. `inferno-flamegraph`) take.
+    /// The counters don't record which function called which, so every line is a `plugin;function instructions` stack
+    /// two frames deep: the flamegraph shows how the instructions are split between the functions, not the call stacks
+    pub fn write_flat_folded<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for function in self.functions.iter().filter(|f| f.instructions > 0) {
+            // `;` separates the frames, and the count is after the last space
+            let frame = |s: &str| s.replace(';', ":");
+            writeln!(w, "{};{} {}", frame(&self.plugin), frame(&function.name), function.instructions)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Profile {
+    /// The functions which ran, the ones which ran the most instructions first
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut functions: Vec<_> = self.functions.iter().filter(|f| f.calls > 0).collect();
+        functions.sort_by_key(|f| Reverse(f.instructions));
+        writeln!(f, "{:>12} {:>8}  function", "instructions", "calls")?;
+        for function in functions {
+            writeln!(f, "{:>12} {:>8}  {}", function.instructions, function.calls, function.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The module couldn't be instrumented, as it isn't valid
+#[derive(Debug)]
+pub(crate) struct InstrumentError(String);
+
+impl From<BinaryReaderError> for InstrumentError {
+    fn from(e: BinaryReaderError) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl fmt::Display for InstrumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Adds a call counter and an instruction counter to each function defined by the module, as exported mutable globals.
+///
+/// Each function increments its call counter when it starts, and adds the number of instructions it ran to its instruction counter
+/// before each instruction which may leave the current block (like wasmer's metering), so the counters are exact
+/// as long as the function doesn't trap. The bytes are rewritten before compiling, so no compiler support is needed.
+pub(crate) fn instrument(bytes: &[u8]) -> Result<Vec<u8>, InstrumentError> {
+    let error = |message: &str| InstrumentError(message.to_string());
+    let mut rest = match bytes {
+        [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, rest @ ..] => rest,
+        _ => return Err(error("not a wasm module")),
+    };
+    let mut sections = Vec::new();
+    while !rest.is_empty() {
+        let id = rest[0];
+        rest = &rest[1..];
+        let len = leb128::read(&mut rest).ok_or_else(|| error("invalid section size"))? as usize;
+        if rest.len() < len {
+            return Err(error("section extends past the end of the module"));
+        }
+        let (section, after) = rest.split_at(len);
+        sections.push((id, section.to_vec()));
+        rest = after;
+    }
+
+    let mut imported_functions = 0;
+    let mut imported_globals = 0;
+    let mut names = Vec::new();
+    for (id, section) in &sections {
+        match *id {
+            SECTION_IMPORT => {
+                for import in ImportSectionReader::new(section, 0)? {
+                    match import?.ty {
+                        ImportSectionEntryType::Function(_) => imported_functions += 1,
+                        ImportSectionEntryType::Global(_) => imported_globals += 1,
+                        _ => (),
+                    }
+                }
+            }
+            SECTION_CUSTOM => {
+                let mut data = section.as_slice();
+                let name_len = leb128::read(&mut data).ok_or_else(|| error("invalid custom section name"))? as usize;
+                if data.len() >= name_len && &data[..name_len] == b"name" {
+                    names = function_names(&data[name_len..])?;
+                }
+            }
+            _ => (),
+        }
+    }
+    let defined_globals = sections
+        .iter()
+        .find(|(id, _)| *id == SECTION_GLOBAL)
+        .map_or(Some(0), |(_, section)| leb128::read(&mut section.as_slice()))
+        .ok_or_else(|| error("invalid global section"))?;
+    let (functions, code) = match sections.iter().position(|(id, _)| *id == SECTION_CODE) {
+        Some(i) => {
+            let mut section = sections[i].1.as_slice();
+            let count = leb128::read(&mut section).ok_or_else(|| error("invalid code section"))?;
+            (count, i)
+        }
+        // Nothing to profile
+        None => return Ok(bytes.to_vec()),
+    };
+    // The counters of the function defined at position i are the globals 2i and 2i + 1 after the existing ones
+    let first_counter = imported_globals + defined_globals;
+
+    let mut code_section = Vec::new();
+    leb128::write(&mut code_section, functions);
+    let mut bodies = &sections[code].1[..];
+    leb128::read(&mut bodies);
+    for i in 0..functions {
+        let len = leb128::read(&mut bodies).ok_or_else(|| error("invalid function body size"))? as usize;
+        if bodies.len() < len {
+            return Err(error("function body extends past the code section"));
+        }
+        let (body, after) = bodies.split_at(len);
+        bodies = after;
+        let body = instrument_body(body, first_counter + 2 * i, first_counter + 2 * i + 1)?;
+        leb128::write(&mut code_section, body.len() as u32);
+        code_section.extend(body);
+    }
+    sections[code].1 = code_section;
+
+    // The counters are exported so that the host can read them
+    let mut globals = Vec::new();
+    let mut exports = Vec::new();
+    for i in 0..functions as usize {
+        for (j, export) in [calls_export(i), instructions_export(i)].iter().enumerate() {
+            // A mutable i64 initialized to 0
+            globals.extend_from_slice(&[0x7e, 0x01, 0x42, 0x00, 0x0b]);
+            write_name(&mut exports, export);
+            exports.push(0x03);
+            leb128::write(&mut exports, first_counter + 2 * i as u32 + j as u32);
+        }
+    }
+    append_entries(&mut sections, SECTION_GLOBAL, 2 * functions, &globals)?;
+    append_entries(&mut sections, SECTION_EXPORT, 2 * functions, &exports)?;
+
+    let mut profile_section = Vec::new();
+    write_name(&mut profile_section, PROFILE_SECTION);
+    for i in 0..functions {
+        let index = imported_functions + i;
+        let name = names
+            .iter()
+            .find(|(j, _)| *j == index)
+            .map_or_else(|| format!("function[{}]", index), |(_, name)| name.clone());
+        // One name per line
+        profile_section.extend(name.replace('\n', " ").as_bytes());
+        profile_section.push(b'\n');
+    }
+    sections.push((SECTION_CUSTOM, profile_section));
+
+    let mut module = bytes[..8].to_vec();
+    for (id, section) in sections {
+        module.push(id);
+        leb128::write(&mut module, section.len() as u32);
+        module.extend(section);
+    }
+    Ok(module)
+}
+
+/// Adds the instructions incrementing the call counter at the start of the body, and the instruction counter at the end of each block
+fn instrument_body(body: &[u8], calls: u32, instructions: u32) -> Result<Vec<u8>, InstrumentError> {
+    let add = |out: &mut Vec<u8>, global: u32, value: u64| {
+        // global.get, i64.const, i64.add, global.set
+        out.push(0x23);
+        leb128::write(out, global);
+        out.push(0x42);
+        leb128::write_signed(out, value as i64);
+        out.push(0x7c);
+        out.push(0x24);
+        leb128::write(out, global);
+    };
+
+    let mut operators = FunctionBody::new(0, body).get_operators_reader()?;
+    let start = operators.original_position();
+    let mut out = body[..start].to_vec();
+    add(&mut out, calls, 1);
+    let mut copied = start;
+    let mut cost = 0;
+    while !operators.eof() {
+        let (operator, offset) = operators.read_with_offset()?;
+        cost += 1;
+        let leaves_block = matches!(
+            operator,
+            Operator::Loop { .. }
+                | Operator::End
+                | Operator::Else
+                | Operator::Br { .. }
+                | Operator::BrIf { .. }
+                | Operator::BrTable { .. }
+                | Operator::Call { .. }
+                | Operator::CallIndirect { .. }
+                | Operator::Return
+                | Operator::Unreachable
+        );
+        if leaves_block {
+            out.extend_from_slice(&body[copied..offset]);
+            add(&mut out, instructions, cost);
+            copied = offset;
+            cost = 0;
+        }
+    }
+    out.extend_from_slice(&body[copied..]);
+    Ok(out)
+}
+
+/// The function names of the name section, by function index
+fn function_names(data: &[u8]) -> Result<Vec<(u32, String)>, InstrumentError> {
+    let mut reader = NameSectionReader::new(data, 0)?;
+    let mut names = Vec::new();
+    while !reader.eof() {
+        if let Name::Function(functions) = reader.read()? {
+            let mut map = functions.get_map()?;
+            for _ in 0..map.get_count() {
+                let naming = map.read()?;
+                names.push((naming.index, naming.name.to_string()));
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Adds entries at the end of the section with this id (which starts with the number of entries), creating it if there is none
+fn append_entries(sections: &mut Vec<(u8, Vec<u8>)>, id: u8, count: u32, entries: &[u8]) -> Result<(), InstrumentError> {
+    match sections.iter_mut().find(|(i, _)| *i == id) {
+        Some((_, section)) => {
+            let mut rest = section.as_slice();
+            let existing =
+                leb128::read(&mut rest).ok_or_else(|| InstrumentError("invalid section entry count".to_string()))?;
+            let mut new = Vec::new();
+            leb128::write(&mut new, existing + count);
+            new.extend_from_slice(rest);
+            new.extend_from_slice(entries);
+            *section = new;
+        }
+        None => {
+            let mut new = Vec::new();
+            leb128::write(&mut new, count);
+            new.extend_from_slice(entries);
+            // The known sections have to be in order, custom sections can be anywhere
+            let position = sections
+                .iter()
+                .position(|(i, _)| *i != SECTION_CUSTOM && section_order(*i) > section_order(id))
+                .unwrap_or(sections.len());
+            sections.insert(position, (id, new));
+        }
+    }
+    Ok(())
+}
+
+/// The position the known sections must appear in
+fn section_order(id: u8) -> u8 {
+    match id {
+        // The tag section goes between the memory and global sections, and the data count one between the element and code ones
+        13 => 6,
+        1..=5 => id,
+        6..=9 => id + 1,
+        12 => 11,
+        10 | 11 => id + 2,
+        _ => u8::MAX,
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    leb128::write(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_plugins, CompiledPlugin, Engine};
+
+    fn module() -> Vec<u8> {
+        test_plugins::plugin(
+            r#"
+            (func $double (param i32) (result i32)
+                (i32.add (local.get 0) (local.get 0)))
+            (func (export "quadruple") (param i32 i32 i32) (result i32)
+                (call $double (call $double (local.get 0))))
+            (func (export "sum") (param $n i32) (param i32 i32) (result i32)
+                (local $sum i32)
+                (block $done
+                    (loop $next
+                        (br_if $done (i32.eqz (local.get $n)))
+                        (local.set $sum (i32.add (local.get $sum) (local.get $n)))
+                        (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+                        (br $next)))
+                (local.get $sum))
+            "#,
+            &[test_plugins::plugin_record("profiled", "1.0.0")],
+        )
+    }
+
+    fn function<'a>(profile: &'a Profile, name: &str) -> &'a FunctionProfile {
+        profile.functions.iter().find(|f| f.name == name).unwrap()
+    }
+
+    #[test]
+    fn instrumented_modules_are_valid() {
+        let instrumented = instrument(&module()).unwrap();
+        wasmer::wasmparser::validate(&instrumented).unwrap();
+        // The instrumented module is still a plugin
+        let metadata = crate::PluginMetadata::from_bytes(&instrumented).unwrap();
+        assert_eq!(metadata.info.name, "profiled");
+    }
+
+    #[test]
+    fn counts_the_calls_and_instructions_of_each_function() {
+        let compiled = CompiledPlugin::new(&Engine::new().with_profiling(), &module());
+        let instance = compiled.instantiate();
+        let call = |name: &str, n: i32| {
+            let result = instance.exports.get_function(name).unwrap().call(&[Val::I32(n), Val::I32(0), Val::I32(0)]);
+            result.unwrap()[0].unwrap_i32()
+        };
+        assert_eq!(call("quadruple", 3), 12);
+        assert_eq!(call("sum", 3), 6);
+
+        let profile = compiled.profile(&instance).unwrap();
+        assert_eq!(profile.plugin, "profiled");
+        let double = function(&profile, "double");
+        // local.get, local.get, i32.add and end
+        assert_eq!((double.calls, double.instructions), (2, 8));
+        // The exported functions have no name in the name section, and come after WASI's proc_exit and the ABI functions
+        let quadruple = function(&profile, "function[4]");
+        // local.get, call, call and end
+        assert_eq!((quadruple.calls, quadruple.instructions), (1, 4));
+        // block and loop, 4 times the 3 instructions of the condition, 3 times the 9 of the body, and the result and end,
+        // as the branches skip the ends of the block and the loop
+        let sum = function(&profile, "function[5]");
+        assert_eq!((sum.calls, sum.instructions), (1, 2 + 4 * 3 + 3 * 9 + 2));
+        assert_eq!(function(&profile, "function[2]").calls, 0);
+
+        compiled.reset_profile(&instance);
+        let profile = compiled.profile(&instance).unwrap();
+        assert!(profile.functions.iter().all(|f| f.calls == 0 && f.instructions == 0));
+    }
+
+    #[test]
+    fn plugins_compiled_without_profiling_have_no_profile() {
+        let compiled = CompiledPlugin::new(&Engine::new(), &module());
+        assert_eq!(compiled.profile(&compiled.instantiate()), None);
+    }
+
+    #[test]
+    fn writes_flat_folded_stacks() {
+        let mut profile = Profile {
+            plugin: "a;b".to_string(),
+            functions: vec![
+                FunctionProfile { name: "f".to_string(), calls: 1, instructions: 10 },
+                FunctionProfile { name: "g".to_string(), calls: 0, instructions: 0 },
+            ],
+        };
+        profile.merge(&profile.clone());
+        let mut folded = Vec::new();
+        profile.write_flat_folded(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "a:b;f 20\n");
+        assert_eq!(profile.functions[0].calls, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "another plugin or module")]
+    fn only_merges_the_profiles_of_the_same_module() {
+        let function = |name: &str| FunctionProfile { name: name.to_string(), calls: 1, instructions: 1 };
+        let mut profile = Profile { plugin: "a".to_string(), functions: vec![function("f"), function("g")] };
+        profile.merge(&Profile { plugin: "a".to_string(), functions: vec![function("g"), function("f")] });
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        assert!(instrument(b"not wasm").is_err());
+        let mut truncated = module();
+        truncated.truncate(truncated.len() / 2);
+        assert!(instrument(&truncated).is_err());
+    }
+}
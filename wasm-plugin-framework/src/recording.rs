@@ -0,0 +1,200 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    abi,
+    loader::{CompiledPlugin, InstanceLoader},
+};
+
+/// A call made to an API function of a plugin, with the arguments and result as they went through the plugin ABI
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedCall {
+    pub plugin: String,
+    pub plugin_version: String,
+    pub api_name: String,
+    pub api_version: String,
+    pub function: String,
+    /// The encoded arguments, each of them on its own like they are copied into the plugin memory
+    pub args: Vec<Vec<u8>>,
+    /// The encoded result (empty for functions without one), or the error the call failed with
+    pub result: Result<Vec<u8>, String>,
+    pub duration: Duration,
+}
+
+/// Records the API calls made to the plugins to a file, to replay them later against another build of the plugin with [`replay`].
+///
+/// Each call is written as it returns, as a u32 (little endian) length followed by the encoded [`RecordedCall`].
+/// Failing to write a call doesn't fail it, the recording will just miss it.
+/// Cloning it is cheap, as the clones write to the same file.
+#[derive(Clone)]
+pub struct CallRecorder {
+    file: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl CallRecorder {
+    /// Creates the file, truncating it if it exists
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+
+    /// Opens the file to add calls at its end, creating it if it doesn't exist
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    fn new(file: File) -> Self {
+        Self {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+        }
+    }
+
+    pub(crate) fn record(&self, call: &RecordedCall) {
+        let bytes = abi::into_bytes(call);
+        let mut file = self.file.lock().unwrap();
+        // Flushed after each call, so that the recording is complete even if the host crashes right after
+        let _ = file
+            .write_all(&(bytes.len() as u32).to_le_bytes())
+            .and_then(|_| file.write_all(&bytes))
+            .and_then(|_| file.flush());
+    }
+}
+
+/// Reads the calls written by a [`CallRecorder`], in the order they were made
+pub fn read_recording<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedCall>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut calls = Vec::new();
+    let mut len = [0; 4];
+    loop {
+        match file.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(calls),
+            Err(e) => return Err(e),
+        }
+        // Not allocated up front, a corrupted length could be up to 4 GiB
+        let len = u32::from_le_bytes(len);
+        let mut bytes = Vec::new();
+        (&mut file).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the recording ends in the middle of a call"));
+        }
+        calls.push(abi::try_from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+    }
+}
+
+/// A recorded call which gave a different result when replayed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayMismatch {
+    /// The position of the call in the recording
+    pub index: usize,
+    pub call: RecordedCall,
+    /// The encoded result of the replayed call, or the error it failed with
+    pub replayed: Result<Vec<u8>, String>,
+}
+
+/// Makes the recorded calls again, in order, on a new instance of the plugin, and returns the ones whose results differ.
+///
+/// The results are compared as encoded bytes, so the plugin can be any build implementing the same API versions.
+/// The instance doesn't have the plugin dependencies, so the calls which use them fail.
+/// Calls recorded from different instances (eg. from a pool) are all made on the same instance,
+/// so plugins keeping state between calls may give different results.
+pub fn replay(compiled: &CompiledPlugin, calls: &[RecordedCall]) -> Vec<ReplayMismatch> {
+    let instance = compiled.instantiate();
+    let loader = InstanceLoader(&instance);
+    calls
+        .iter()
+        .enumerate()
+        .filter_map(|(index, call)| {
+            let api = compiled
+                .apis()
+                .iter()
+                .find(|api| api.name == call.api_name && api.version == call.api_version && api.functions.contains(&call.function));
+            let replayed = match api {
+                Some(api) => instance
+                    .exports
+                    .get_function(&api.symbol(&call.function))
+                    .map_err(|e| e.to_string())
+                    .and_then(|function| abi::call_encoded(&loader, function, &call.args).map_err(|e| e.message())),
+                None => Err(format!(
+                    "The plugin doesn't provide the function {} of the API {} {}",
+                    call.function, call.api_name, call.api_version
+                )),
+            };
+            if replayed == call.result {
+                None
+            } else {
+                Some(ReplayMismatch {
+                    index,
+                    call: call.clone(),
+                    replayed,
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        loader::Engine,
+        test_plugins::{api_record, plugin, plugin_record},
+    };
+
+    fn call(args: Vec<Vec<u8>>, result: Result<Vec<u8>, String>) -> RecordedCall {
+        RecordedCall {
+            plugin: "echo".to_string(),
+            plugin_version: "0.1.0".to_string(),
+            api_name: "echo".to_string(),
+            api_version: "1.0.0".to_string(),
+            function: "echo".to_string(),
+            args,
+            result,
+            duration: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn reads_the_recorded_calls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("calls");
+        let recorder = CallRecorder::create(&path).unwrap();
+        let calls = [call(vec![vec![1, 2]], Ok(vec![1, 2])), call(Vec::new(), Err("trap".to_string()))];
+        for call in &calls {
+            recorder.clone().record(call);
+        }
+        assert_eq!(read_recording(&path).unwrap(), calls);
+    }
+
+    #[test]
+    fn rejects_truncated_recordings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("calls");
+        // A length of 4 GiB, followed by a few bytes
+        std::fs::write(&path, [0xff, 0xff, 0xff, 0xff, 1, 2, 3]).unwrap();
+        assert_eq!(read_recording(&path).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn replays_the_calls_and_returns_the_different_results() {
+        // Returns its argument buffer, which is laid out like a result
+        let module = plugin(
+            r#"(func (export "__wpf__echo__1_2e0_2e0__fn_echo") (param i32) (result i32) (local.get 0))"#,
+            &[plugin_record("echo", "0.1.0"), api_record("echo", "1.0.0", &["echo"])],
+        );
+        let compiled = CompiledPlugin::new(&Engine::new(), &module);
+        let mut missing = call(Vec::new(), Ok(Vec::new()));
+        missing.function = "missing".to_string();
+        let calls = [call(vec![vec![1, 2, 3]], Ok(vec![1, 2, 3])), call(vec![vec![4]], Ok(vec![5])), missing];
+        let mismatches = replay(&compiled, &calls);
+        assert_eq!(mismatches.iter().map(|m| m.index).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(mismatches[0].replayed, Ok(vec![4]));
+        assert!(mismatches[1].replayed.is_err());
+    }
+}
@@ -0,0 +1,28 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A plugin instance which can be shared between threads.
+/// Calls to it are serialized by a mutex, as a single instance can't run two calls at once.
+pub struct SharedPlugin<L> {
+    inner: Arc<Mutex<L>>,
+}
+
+impl<L> SharedPlugin<L> {
+    pub fn new(plugin: L) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(plugin)),
+        }
+    }
+
+    /// Locks the instance, blocking until no other thread is using it
+    pub fn lock(&self) -> MutexGuard<'_, L> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<L> Clone for SharedPlugin<L> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The number of buckets of a [`LatencyHistogram`], one for each power of two of nanoseconds a u64 can hold
+const BUCKETS: usize = 64;
+
+/// A histogram of the latencies of a phase of the calls.
+/// The buckets grow in powers of two (bucket `i` holds the latencies from 2^i to 2^(i+1) nanoseconds),
+/// so the percentiles are approximate, but recording a latency never allocates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            total: Duration::ZERO,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = (BUCKETS - 1).saturating_sub(nanos.leading_zeros() as usize);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.min)
+        }
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.max)
+        }
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64))
+        }
+    }
+
+    /// The latency under which the given fraction (between 0 and 1) of the calls are, rounded up to the bucket it falls in
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((fraction.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (upper, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return Some(upper.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// The upper bound and number of latencies of each bucket, from the shortest latencies to the longest
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, &count)| {
+            let upper = 1u64.checked_shl(i as u32 + 1).unwrap_or(u64::MAX);
+            (Duration::from_nanos(upper), count)
+        })
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The statistics of the calls to an API function of a plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub api_name: String,
+    pub api_version: String,
+    pub function: String,
+    pub calls: u64,
+    /// The calls which trapped, their latencies are not recorded
+    pub errors: u64,
+    /// The encoded size of all the arguments of all the calls
+    pub args_bytes: u64,
+    /// The encoded size of all the results
+    pub result_bytes: u64,
+    /// Encoding the arguments and copying them into the plugin memory, not counting the wait for the instance lock
+    pub serialize: LatencyHistogram,
+    /// Waiting for the calls made on the same instance from other threads to finish
+    pub lock_wait: LatencyHistogram,
+    /// Running the function in the plugin
+    pub guest: LatencyHistogram,
+    /// Copying the result out of the plugin memory and decoding it
+    pub deserialize: LatencyHistogram,
+}
+
+/// A snapshot of the statistics of the calls made to a plugin, through all the loaders of the same compiled plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginStats {
+    pub plugin: String,
+    /// The functions which were called, sorted by API and function name
+    pub functions: Vec<FunctionStats>,
+}
+
+impl PluginStats {
+    /// The statistics of the function with this name, in any of the APIs
+    pub fn function(&self, name: &str) -> Option<&FunctionStats> {
+        self.functions.iter().find(|f| f.function == name)
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.functions.iter().map(|f| f.calls).sum()
+    }
+}
+
+/// The time spent in each phase of a call
+pub(crate) struct CallTimings {
+    pub(crate) serialize: Duration,
+    pub(crate) lock_wait: Duration,
+    pub(crate) guest: Duration,
+    pub(crate) deserialize: Duration,
+}
+
+/// The API name, API version and function name
+type FunctionKey = (&'static str, &'static str, &'static str);
+
+/// Collects the statistics of a compiled plugin. Cloning it is cheap, as the clones collect into the same statistics
+#[derive(Clone, Default)]
+pub(crate) struct StatsCollector {
+    functions: Arc<Mutex<HashMap<FunctionKey, FunctionStats>>>,
+}
+
+impl StatsCollector {
+    fn entry<F: FnOnce(&mut FunctionStats)>(&self, api_name: &'static str, api_version: &'static str, function: &'static str, f: F) {
+        let mut functions = self.functions.lock().unwrap();
+        let stats = functions
+            .entry((api_name, api_version, function))
+            .or_insert_with(|| FunctionStats {
+                api_name: api_name.to_string(),
+                api_version: api_version.to_string(),
+                function: function.to_string(),
+                calls: 0,
+                errors: 0,
+                args_bytes: 0,
+                result_bytes: 0,
+                serialize: LatencyHistogram::new(),
+                lock_wait: LatencyHistogram::new(),
+                guest: LatencyHistogram::new(),
+                deserialize: LatencyHistogram::new(),
+            });
+        stats.calls += 1;
+        f(stats);
+    }
+
+    pub(crate) fn record(
+        &self,
+        api_name: &'static str,
+        api_version: &'static str,
+        function: &'static str,
+        args_bytes: usize,
+        result_bytes: usize,
+        timings: &CallTimings,
+    ) {
+        self.entry(api_name, api_version, function, |stats| {
+            stats.args_bytes += args_bytes as u64;
+            stats.result_bytes += result_bytes as u64;
+            stats.serialize.record(timings.serialize);
+            stats.lock_wait.record(timings.lock_wait);
+            stats.guest.record(timings.guest);
+            stats.deserialize.record(timings.deserialize);
+        })
+    }
+
+    pub(crate) fn record_error(&self, api_name: &'static str, api_version: &'static str, function: &'static str, args_bytes: usize) {
+        self.entry(api_name, api_version, function, |stats| {
+            stats.errors += 1;
+            stats.args_bytes += args_bytes as u64;
+        })
+    }
+
+    pub(crate) fn snapshot(&self, plugin: &str) -> PluginStats {
+        let mut functions: Vec<FunctionStats> = self.functions.lock().unwrap().values().cloned().collect();
+        functions.sort_by(|a, b| {
+            (&a.api_name, &a.api_version, &a.function).cmp(&(&b.api_name, &b.api_version, &b.function))
+        });
+        PluginStats {
+            plugin: plugin.to_string(),
+            functions,
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.functions.lock().unwrap().clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(nanos: &[u64]) -> LatencyHistogram {
+        let mut histogram = LatencyHistogram::new();
+        for &n in nanos {
+            histogram.record(Duration::from_nanos(n));
+        }
+        histogram
+    }
+
+    #[test]
+    fn empty_histograms_have_no_latencies() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!((histogram.min(), histogram.max(), histogram.mean()), (None, None, None));
+        assert_eq!(histogram.percentile(0.5), None);
+    }
+
+    #[test]
+    fn records_the_latencies_in_power_of_two_buckets() {
+        let histogram = histogram(&[0, 1, 3, 4, 7, 1000, u64::MAX]);
+        let counts: Vec<(Duration, u64)> = histogram.buckets().filter(|(_, count)| *count > 0).collect();
+        assert_eq!(
+            counts,
+            [
+                (Duration::from_nanos(2), 2),
+                (Duration::from_nanos(4), 1),
+                (Duration::from_nanos(8), 2),
+                (Duration::from_nanos(1024), 1),
+                (Duration::from_nanos(u64::MAX), 1),
+            ]
+        );
+        assert_eq!(histogram.buckets().count(), BUCKETS);
+        assert_eq!(histogram.min(), Some(Duration::ZERO));
+        assert_eq!(histogram.max(), Some(Duration::from_nanos(u64::MAX)));
+    }
+
+    #[test]
+    fn percentiles_are_rounded_up_to_the_bucket_and_capped_by_the_max() {
+        let histogram = histogram(&[100, 100, 100, 1000]);
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.total(), Duration::from_nanos(1300));
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(325)));
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_nanos(128)));
+        assert_eq!(histogram.percentile(0.75), Some(Duration::from_nanos(128)));
+        // The last bucket goes up to 1024, but no latency was over 1000
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_nanos(1000)));
+        assert_eq!(histogram.percentile(2.0), Some(Duration::from_nanos(1000)));
+    }
+
+    #[test]
+    fn collects_the_stats_of_each_function() {
+        let collector = StatsCollector::default();
+        let timings = CallTimings {
+            serialize: Duration::from_nanos(10),
+            lock_wait: Duration::from_nanos(0),
+            guest: Duration::from_nanos(20),
+            deserialize: Duration::from_nanos(30),
+        };
+        collector.clone().record("api", "1.0.0", "b", 4, 8, &timings);
+        collector.record("api", "1.0.0", "a", 1, 2, &timings);
+        collector.record("api", "1.0.0", "b", 4, 8, &timings);
+        collector.record_error("api", "1.0.0", "b", 4);
+
+        let stats = collector.snapshot("plugin");
+        assert_eq!(stats.calls(), 4);
+        assert_eq!(stats.functions.iter().map(|f| f.function.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        let b = stats.function("b").unwrap();
+        assert_eq!((b.calls, b.errors, b.args_bytes, b.result_bytes), (3, 1, 12, 16));
+        assert_eq!(b.guest.count(), 2);
+        assert_eq!(b.deserialize.max(), Some(Duration::from_nanos(30)));
+
+        collector.reset();
+        assert!(collector.snapshot("plugin").functions.is_empty());
+    }
+}
@@ -0,0 +1,62 @@
+//! Small plugin modules written in the wasm text format, for the unit tests
+
+use crate::{abi::ABI_VERSION, metadata::SECTION};
+
+/// The memory and buffer functions every plugin exports, with a bump allocator which never frees.
+/// The plugins need to import something from WASI for the loader to find its version
+pub(crate) const ABI: &str = r#"
+    (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+    (memory (export "memory") 1)
+    (global $next (mut i32) (i32.const 1024))
+    (func (export "allocate_buffer") (param $size i32) (result i32)
+        (local $ptr i32)
+        (local.set $ptr (global.get $next))
+        (global.set $next (i32.add (global.get $next) (local.get $size)))
+        (local.get $ptr))
+    (func (export "free_buffer") (param i32 i32))
+"#;
+
+/// Encodes a metadata record, like the macros do
+pub(crate) fn record(kind: &str, fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let abi_version = ABI_VERSION.to_string();
+    let header = [("kind", kind), ("abi_version", abi_version.as_str())];
+    for s in header.iter().chain(fields).flat_map(|(key, value)| vec![*key, *value]) {
+        body.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        body.extend_from_slice(s.as_bytes());
+    }
+    let mut record = (body.len() as u32).to_le_bytes().to_vec();
+    record.extend(body);
+    record
+}
+
+pub(crate) fn plugin_record(name: &str, version: &str) -> Vec<u8> {
+    record("plugin", &[("name", name), ("version", version)])
+}
+
+pub(crate) fn api_record(name: &str, version: &str, functions: &[&str]) -> Vec<u8> {
+    record("api", &[("name", name), ("version", version), ("functions", &functions.join(","))])
+}
+
+/// Appends a custom section to the module
+pub(crate) fn with_custom_section(mut module: Vec<u8>, name: &str, contents: &[u8]) -> Vec<u8> {
+    let mut section = Vec::new();
+    crate::leb128::write(&mut section, name.len() as u32);
+    section.extend_from_slice(name.as_bytes());
+    section.extend_from_slice(contents);
+    module.push(0);
+    crate::leb128::write(&mut module, section.len() as u32);
+    module.extend(section);
+    module
+}
+
+/// A module with the ABI functions and the body, and the records in its metadata section
+pub(crate) fn plugin(body: &str, records: &[Vec<u8>]) -> Vec<u8> {
+    plugin_importing("", body, records)
+}
+
+/// Like `plugin`, with imports, which have to come before the ABI functions
+pub(crate) fn plugin_importing(imports: &str, body: &str, records: &[Vec<u8>]) -> Vec<u8> {
+    let module = wat::parse_str(format!("(module {} {} {})", imports, ABI, body)).expect("Invalid test module");
+    with_custom_section(module, SECTION, &records.concat())
+}
@@ -0,0 +1,102 @@
+//! Helpers to test the plugins natively. The common library generates a native loader for each API (`<Api>Native<T>`),
+//! which implements the host trait for any implementation of the API, calling it through [`round_trip`],
+//! and a mock (`<Api>Mock`) to test the host code using the API without any plugin.
+//! Plugin crates building for wasm by default can run their tests with `cargo test --target <host triple>`,
+//! like the `test-native` alias of the example plugin.
+
+use std::cell::Cell;
+
+use serde::{Deserialize, Serialize};
+use wasmer::{Memory, MemoryType, Pages, Store, JIT, WASM_PAGE_SIZE};
+
+use crate::abi::{self, PluginLoader};
+
+/// A memory in the host process standing in for the one of a plugin instance, with a bump allocator.
+/// The buffers have to be freed in the reverse order of their allocation, with the size they were allocated with
+struct NativeMemory {
+    memory: Memory,
+    next: Cell<u32>,
+}
+
+impl NativeMemory {
+    fn new() -> Self {
+        // Nothing is compiled, the store doesn't need a compiler
+        let store = Store::new(&JIT::headless().engine());
+        let memory = Memory::new(&store, MemoryType::new(1, None, false)).expect("Error creating the native memory");
+        // Not starting at 0, so that a null pointer isn't a valid buffer
+        Self { memory, next: Cell::new(8) }
+    }
+}
+
+impl PluginLoader for NativeMemory {
+    fn allocate_buffer(&self, size: u32) -> u32 {
+        let ptr = self.next.get();
+        let end = ptr.checked_add(size).expect("The buffer doesn't fit in a wasm memory");
+        let pages = (end as usize).div_ceil(WASM_PAGE_SIZE) as u32;
+        let current = self.memory.size().0;
+        if pages > current {
+            self.memory.grow(Pages(pages - current)).expect("The buffer doesn't fit in a wasm memory");
+        }
+        self.next.set(end);
+        ptr
+    }
+
+    fn free_buffer(&self, ptr: u32, size: u32) {
+        assert_eq!(ptr + size, self.next.get(), "Freed a buffer which isn't the last one allocated, or with the wrong size");
+        self.next.set(ptr);
+    }
+
+    fn memory(&self) -> &Memory {
+        &self.memory
+    }
+}
+
+thread_local! {
+    static MEMORY: NativeMemory = NativeMemory::new();
+}
+
+/// Passes the value through the plugin ABI in-process: it is copied into a buffer allocated in a wasm memory,
+/// as a length and the encoded value, then read back and freed, like the arguments and results of the plugin calls.
+/// Used by the native loaders to test the plugins without a wasm toolchain
+pub fn round_trip<T>(value: &T) -> T
+where
+    T: Serialize + for<'a> Deserialize<'a>,
+{
+    MEMORY.with(|memory| {
+        let ptr = abi::into_abi(memory, value);
+        abi::from_abi(memory, ptr)
+    })
+}
+
+/// Encodes the value like the ABI does, eg. to record the arguments of the calls to a mock
+pub fn encode<T>(value: &T) -> Vec<u8>
+where
+    T: Serialize + ?Sized,
+{
+    abi::into_bytes(value)
+}
+
+/// Decodes a value encoded with [`encode`]
+pub fn decode<T>(bytes: &[u8]) -> T
+where
+    T: for<'a> Deserialize<'a>,
+{
+    abi::from_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_go_through_a_wasm_memory() {
+        assert_eq!(round_trip(&(1u8, "two".to_string(), vec![3u64; 3])), (1, "two".to_string(), vec![3; 3]));
+        // Bigger than the first page
+        let big = vec![7u8; 3 * WASM_PAGE_SIZE];
+        assert_eq!(round_trip(&big), big);
+        MEMORY.with(|memory| {
+            assert_eq!(memory.next.get(), 8);
+            assert!(memory.memory.size().0 > 1);
+        });
+    }
+}
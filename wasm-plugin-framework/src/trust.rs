@@ -0,0 +1,158 @@
+use std::{convert::TryFrom, fmt};
+
+use ed25519_dalek::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+/// The plugins an engine accepts to compile, checked on the module bytes before compiling them.
+///
+/// A module is trusted if its SHA-256 hash is pinned, or if it comes with a detached Ed25519 signature
+/// of its bytes by one of the trusted keys. The signatures are made with `wasm-plugin sign`.
+/// For a package, the hash and signature are the ones of its whole contents, as given by `PluginPackage::signed_contents`.
+#[derive(Debug, Clone, Default)]
+pub struct TrustPolicy {
+    keys: Vec<PublicKey>,
+    hashes: Vec<[u8; 32]>,
+}
+
+impl TrustPolicy {
+    /// A policy which doesn't trust any plugin, until keys or hashes are added
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the plugins signed with the secret key of this public key, given as 64 hex characters
+    pub fn with_public_key(mut self, key: &str) -> Result<Self, InvalidTrustEntry> {
+        let bytes = decode_32(key).ok_or_else(|| InvalidTrustEntry(key.to_string()))?;
+        let key = PublicKey::from_bytes(&bytes).map_err(|_| InvalidTrustEntry(key.to_string()))?;
+        self.keys.push(key);
+        Ok(self)
+    }
+
+    /// Trusts the module (or package) with this SHA-256 hash (as printed by [`module_hash`]), whether it is signed or not
+    pub fn with_pinned_hash(mut self, hash: &str) -> Result<Self, InvalidTrustEntry> {
+        let hash = decode_32(hash).ok_or_else(|| InvalidTrustEntry(hash.to_string()))?;
+        self.hashes.push(hash);
+        Ok(self)
+    }
+
+    /// Checks that the module (or the signed contents of a package) is trusted, with its detached signature if it has one
+    pub fn verify(&self, bytes: &[u8], signature: Option<&[u8]>) -> Result<(), Untrusted> {
+        let hash: [u8; 32] = Sha256::digest(bytes).into();
+        if self.hashes.contains(&hash) {
+            return Ok(());
+        }
+        let sha256 = hex::encode(hash);
+        let signature = signature.ok_or_else(|| Untrusted::Unsigned { sha256: sha256.clone() })?;
+        let signature = Signature::try_from(signature).map_err(|_| Untrusted::InvalidSignature { sha256: sha256.clone() })?;
+        if self.keys.iter().any(|key| key.verify_strict(bytes, &signature).is_ok()) {
+            Ok(())
+        } else {
+            Err(Untrusted::InvalidSignature { sha256 })
+        }
+    }
+}
+
+/// The SHA-256 hash of the module bytes (or of the signed contents of a package), as 64 hex characters, to pin it in a [`TrustPolicy`]
+pub fn module_hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+fn decode_32(hex: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(hex.trim(), &mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// A public key or hash given to a [`TrustPolicy`] isn't valid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTrustEntry(pub String);
+
+impl fmt::Display for InvalidTrustEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} isn't a valid Ed25519 public key or SHA-256 hash in hex", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTrustEntry {}
+
+/// A plugin module isn't trusted by the engine's [`TrustPolicy`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Untrusted {
+    /// The module or package doesn't have a signature, and its hash isn't pinned
+    Unsigned { sha256: String },
+    /// The signature isn't one of the module or package by any of the trusted keys, and its hash isn't pinned
+    InvalidSignature { sha256: String },
+}
+
+impl fmt::Display for Untrusted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Untrusted::Unsigned { sha256 } => write!(f, "the plugin {} isn't signed nor pinned", sha256),
+            Untrusted::InvalidSignature { sha256 } => {
+                write!(f, "the plugin {} isn't signed by any of the trusted keys", sha256)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Untrusted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn trusting(keypair: &Keypair) -> TrustPolicy {
+        TrustPolicy::new().with_public_key(&hex::encode(keypair.public.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn trusts_the_pinned_hashes() {
+        let policy = TrustPolicy::new().with_pinned_hash(&module_hash(b"module")).unwrap();
+        assert_eq!(policy.verify(b"module", None), Ok(()));
+        // The signature doesn't matter for a pinned module
+        assert_eq!(policy.verify(b"module", Some(b"not a signature")), Ok(()));
+        assert_eq!(
+            policy.verify(b"other", None),
+            Err(Untrusted::Unsigned { sha256: module_hash(b"other") })
+        );
+    }
+
+    #[test]
+    fn trusts_the_signatures_of_the_trusted_keys() {
+        let trusted = keypair(1);
+        let policy = trusting(&trusted);
+        let signature = trusted.sign(b"module").to_bytes();
+        assert_eq!(policy.verify(b"module", Some(&signature)), Ok(()));
+
+        let invalid = Err(Untrusted::InvalidSignature { sha256: module_hash(b"other") });
+        assert_eq!(policy.verify(b"other", Some(&signature)), invalid);
+        let signature = keypair(2).sign(b"module").to_bytes();
+        assert!(matches!(policy.verify(b"module", Some(&signature)), Err(Untrusted::InvalidSignature { .. })));
+        assert!(matches!(policy.verify(b"module", Some(&signature[1..])), Err(Untrusted::InvalidSignature { .. })));
+        assert!(matches!(policy.verify(b"module", None), Err(Untrusted::Unsigned { .. })));
+    }
+
+    #[test]
+    fn trusts_nothing_by_default() {
+        assert!(TrustPolicy::new().verify(b"", None).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let short = "ab".repeat(31);
+        for entry in [short.as_str(), "not hex", ""].iter() {
+            assert_eq!(TrustPolicy::new().with_pinned_hash(entry).unwrap_err(), InvalidTrustEntry(entry.to_string()));
+            assert!(TrustPolicy::new().with_public_key(entry).is_err());
+        }
+        // Surrounding whitespace, like the newline of a key file, is ignored
+        let key = format!("{}\n", hex::encode(keypair(1).public.as_bytes()));
+        assert!(TrustPolicy::new().with_public_key(&key).is_ok());
+    }
+}
@@ -0,0 +1,18 @@
+use wasm_plugin_framework::{plugin_api, PluginApi};
+
+#[plugin_api(name = "symbols test.api", version = "1.0.0-rc.1")]
+pub trait Api {
+    fn a_function(arg: u32) -> u32;
+}
+
+#[test]
+fn the_macros_export_the_symbols_the_host_calls() {
+    let api = PluginApi {
+        name: metadata::API_NAME.to_string(),
+        version: metadata::API_VERSION.to_string(),
+        functions: vec!["a_function".to_string()],
+    };
+    for (function, symbol) in metadata::EXPORTS {
+        assert_eq!(*symbol, api.symbol(function));
+    }
+}
@@ -0,0 +1,19 @@
+mod first {
+    use wasm_plugin_framework::plugin_api;
+
+    #[plugin_api(name = "FIRST", version = "0.1.0")]
+    pub trait Api {
+        fn a(arg: u32) -> u32;
+    }
+}
+
+mod second {
+    use wasm_plugin_framework::plugin_api;
+
+    #[plugin_api(name = "SECOND", version = "0.1.0")]
+    pub trait Api {
+        fn b(arg: u32) -> u32;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use std::env;
+
+/// Run by cargo for `cargo wasm-plugin <command>`, which passes the subcommand name before the command
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("wasm-plugin") {
+        args.remove(0);
+    }
+    wasm_plugin_framework_cli::main(&args);
+}
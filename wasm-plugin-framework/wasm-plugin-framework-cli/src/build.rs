@@ -0,0 +1,149 @@
+use std::{
+    env, fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use serde_json::Value;
+use wasm_plugin_framework::{
+    wasmer::{Module, Store},
+    PluginMetadata, PluginPackage, PACKAGE_EXTENSION,
+};
+
+use crate::{check::check, strip};
+
+/// The target the plugins are built for by default, they get their host access through WASI
+const DEFAULT_TARGET: &str = "wasm32-wasi";
+
+struct Options {
+    manifest_path: Option<String>,
+    release: bool,
+    target: String,
+    out_dir: PathBuf,
+    strip: bool,
+    /// The directory of the assets to package with the plugin
+    assets: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            manifest_path: None,
+            release: false,
+            target: DEFAULT_TARGET.to_string(),
+            out_dir: PathBuf::from("target/plugins"),
+            strip: false,
+            assets: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or_else(|| format!("expected a value after {}", arg));
+            match arg.as_str() {
+                "--manifest-path" => options.manifest_path = Some(value()?),
+                "--release" => options.release = true,
+                "--target" => options.target = value()?,
+                "--out-dir" => options.out_dir = PathBuf::from(value()?),
+                "--strip" => options.strip = true,
+                "--assets" => options.assets = Some(PathBuf::from(value()?)),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args)?;
+    let modules = cargo_build(&options)?;
+    if modules.is_empty() {
+        return Err("no plugin was built, the plugin crate must have crate-type = [\"cdylib\"]".to_string());
+    }
+    fs::create_dir_all(&options.out_dir).map_err(|e| format!("couldn't create {}: {}", options.out_dir.display(), e))?;
+    for module in modules {
+        let artifact = package(&module, &options)?;
+        println!("{}", artifact.display());
+    }
+    Ok(())
+}
+
+/// Builds the cdylib crates of the manifest for the target, returning the wasm modules cargo produced
+fn cargo_build(options: &Options) -> Result<Vec<PathBuf>, String> {
+    // Cargo sets it when running a subcommand, so the same toolchain builds the plugin
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut command = Command::new(cargo);
+    command
+        .args(["build", "--lib", "--message-format=json-render-diagnostics", "--target"])
+        .arg(&options.target)
+        .stdout(Stdio::piped());
+    if let Some(manifest_path) = &options.manifest_path {
+        command.arg("--manifest-path").arg(manifest_path);
+    }
+    if options.release {
+        command.arg("--release");
+    }
+    let mut child = command.spawn().map_err(|e| format!("couldn't run cargo: {}", e))?;
+
+    let mut modules = Vec::new();
+    let messages = serde_json::Deserializer::from_reader(BufReader::new(child.stdout.take().unwrap())).into_iter::<Value>();
+    for message in messages {
+        let message = message.map_err(|e| format!("couldn't read the output of cargo: {}", e))?;
+        if message["reason"] != "compiler-artifact" {
+            continue;
+        }
+        let is_cdylib = message["target"]["crate_types"]
+            .as_array()
+            .is_some_and(|types| types.iter().any(|t| t == "cdylib"));
+        if !is_cdylib {
+            continue;
+        }
+        for filename in message["filenames"].as_array().into_iter().flatten() {
+            if let Some(filename) = filename.as_str().filter(|f| f.ends_with(".wasm")) {
+                modules.push(PathBuf::from(filename));
+            }
+        }
+    }
+
+    let status = child.wait().map_err(|e| format!("couldn't run cargo: {}", e))?;
+    if status.success() {
+        Ok(modules)
+    } else {
+        Err("cargo couldn't build the plugin".to_string())
+    }
+}
+
+/// Strips the module if asked to, checks it implements its APIs, and writes it to the output directory,
+/// in a package with the assets if there are any
+fn package(module: &Path, options: &Options) -> Result<PathBuf, String> {
+    let mut bytes = fs::read(module).map_err(|e| format!("couldn't read {}: {}", module.display(), e))?;
+    let metadata = PluginMetadata::from_bytes(&bytes).map_err(|e| format!("{}: {}", module.display(), e))?;
+    if metadata.apis.is_empty() {
+        return Err(format!("{} doesn't implement any API", module.display()));
+    }
+    if options.strip {
+        let (stripped, removed) = strip::strip_exports(&bytes, strip::used_by_framework)?;
+        if !removed.is_empty() {
+            eprintln!("Removed the exports {}", removed.join(", "));
+        }
+        bytes = stripped;
+    }
+
+    let compiled = Module::new(&Store::default(), &bytes).map_err(|e| format!("{} isn't a valid wasm module: {}", module.display(), e))?;
+    for api in &metadata.apis {
+        let functions: Vec<&str> = api.functions.iter().map(String::as_str).collect();
+        check(&metadata, &compiled, &api.name, &api.version, &functions)?;
+    }
+
+    let name = format!("{}-{}", metadata.info.name, metadata.info.version);
+    let (artifact, bytes) = match &options.assets {
+        Some(assets) => {
+            let package = PluginPackage::new(bytes)
+                .and_then(|package| package.with_assets_dir(assets))
+                .map_err(|e| format!("couldn't package {}: {}", module.display(), e))?;
+            (options.out_dir.join(format!("{}.{}", name, PACKAGE_EXTENSION)), package.to_bytes())
+        }
+        None => (options.out_dir.join(format!("{}.wasm", name)), bytes),
+    };
+    fs::write(&artifact, &bytes).map_err(|e| format!("couldn't write {}: {}", artifact.display(), e))?;
+    Ok(artifact)
+}
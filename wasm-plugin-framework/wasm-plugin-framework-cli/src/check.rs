@@ -0,0 +1,33 @@
+use wasm_plugin_framework::{
+    wasmer::{ExternType, Module},
+    PluginMetadata,
+};
+
+/// The checks the generated loaders make before binding to a plugin:
+/// the metadata ones of `check_api`, and the exports of the ABI and API functions `from_instance` looks up
+pub(crate) fn check(metadata: &PluginMetadata, module: &Module, api_name: &str, api_version: &str, functions: &[&str]) -> Result<(), String> {
+    metadata
+        .check_api(api_name, api_version, functions)
+        .map_err(|e| format!("not compatible with the API {} {}: {}", api_name, api_version, e))?;
+    let api = metadata
+        .apis
+        .iter()
+        .find(|api| api.name == api_name && api.version == api_version)
+        .unwrap();
+    let missing: Vec<String> = ["allocate_buffer", "free_buffer"]
+        .iter()
+        .map(|name| name.to_string())
+        .chain(api.functions.iter().map(|function| api.symbol(function)))
+        .filter(|name| !module.exports().any(|e| e.name() == name && matches!(e.ty(), ExternType::Function(_))))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "not compatible with the API {} {}: the plugin doesn't export the functions {}",
+            api_name,
+            api_version,
+            missing.join(", ")
+        ))
+    }
+}
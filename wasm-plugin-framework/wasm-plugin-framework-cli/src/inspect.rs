@@ -0,0 +1,152 @@
+use std::fs;
+
+use wasm_plugin_framework::{
+    custom_section_sizes, module_hash,
+    wasmer::{ExternType, MemoryType, Module, Store},
+    PluginMetadata, PluginPackage,
+};
+
+use crate::check::check;
+
+/// The API a plugin is checked against, as given on the command line
+struct ApiArg {
+    name: String,
+    version: String,
+    functions: Vec<String>,
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let (path, api) = match args {
+        [path] => (path, None),
+        [path, flag, name, version, functions @ ..] if flag == "--api" => (
+            path,
+            Some(ApiArg {
+                name: name.clone(),
+                version: version.clone(),
+                functions: functions.to_vec(),
+            }),
+        ),
+        _ => return Err("expected inspect <plugin.wasm> [--api <name> <version> [<function>...]]".to_string()),
+    };
+
+    let mut bytes = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    if !bytes.starts_with(b"\0asm") {
+        let package = PluginPackage::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+        print_package(&package);
+        bytes = package.module().to_vec();
+    }
+    let metadata = PluginMetadata::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let module = Module::new(&Store::default(), &bytes).map_err(|e| format!("{} isn't a valid wasm module: {}", path, e))?;
+
+    print_metadata(&metadata);
+    println!("    SHA-256: {}", module_hash(&bytes));
+    print_apis(&metadata, &module);
+    print_imports(&module);
+    print_memories(&module);
+    println!("Custom sections:");
+    for (name, size) in custom_section_sizes(&bytes).map_err(|e| format!("{}: {}", path, e))? {
+        println!("    {}: {} bytes", name, size);
+    }
+
+    match api {
+        Some(api) => {
+            let functions: Vec<&str> = api.functions.iter().map(String::as_str).collect();
+            check(&metadata, &module, &api.name, &api.version, &functions)?;
+            println!("Compatible with the API {} {}", api.name, api.version);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+fn print_package(package: &PluginPackage) {
+    let manifest = package.manifest();
+    println!("Package: {} {}", manifest.name, manifest.version);
+    println!("    Module: {}", manifest.module);
+    println!("    Signed: {}", if package.signature().is_some() { "yes" } else { "no" });
+    println!("    SHA-256: {}", module_hash(&package.signed_contents()));
+    for (key, value) in &manifest.metadata {
+        println!("    {}: {}", key, value);
+    }
+    println!("Assets:");
+    for (path, contents) in package.assets() {
+        println!("    {}: {} bytes", path, contents.len());
+    }
+}
+
+fn print_metadata(metadata: &PluginMetadata) {
+    let info = &metadata.info;
+    println!("Plugin: {} {}", info.name, info.version);
+    if !info.authors.is_empty() {
+        println!("    Authors: {}", info.authors.join(", "));
+    }
+    if let Some(description) = &info.description {
+        println!("    Description: {}", description);
+    }
+    if let Some(homepage) = &info.homepage {
+        println!("    Homepage: {}", homepage);
+    }
+    if let Some(license) = &info.license {
+        println!("    License: {}", license);
+    }
+    println!("    ABI version: {}", metadata.abi_version);
+    if !metadata.dependencies.is_empty() {
+        println!("Dependencies:");
+        for dependency in &metadata.dependencies {
+            println!("    {}", dependency);
+        }
+    }
+}
+
+fn print_apis(metadata: &PluginMetadata, module: &Module) {
+    for api in &metadata.apis {
+        println!("API: {} {}", api.name, api.version);
+        for function in &api.functions {
+            let symbol = api.symbol(function);
+            match module.exports().find(|e| e.name() == symbol) {
+                Some(export) => println!("    {}: {}", function, describe(export.ty())),
+                None => println!("    {}: missing export {}", function, symbol),
+            }
+        }
+    }
+}
+
+fn print_imports(module: &Module) {
+    println!("Imports:");
+    for import in module.imports() {
+        println!("    {}.{}: {}", import.module(), import.name(), describe(import.ty()));
+    }
+}
+
+fn print_memories(module: &Module) {
+    println!("Memories:");
+    for import in module.imports() {
+        if let ExternType::Memory(memory) = import.ty() {
+            println!("    {}.{} (imported): {}", import.module(), import.name(), limits(memory));
+        }
+    }
+    for export in module.exports() {
+        if let ExternType::Memory(memory) = export.ty() {
+            println!("    {} (exported): {}", export.name(), limits(memory));
+        }
+    }
+}
+
+fn describe(ty: &ExternType) -> String {
+    match ty {
+        ExternType::Function(f) => format!("function {}", f),
+        ExternType::Global(g) => format!("global {}", g),
+        ExternType::Table(t) => format!("table {}", t),
+        ExternType::Memory(m) => format!("memory {}", limits(m)),
+    }
+}
+
+/// The limits of the memory, in 64 KiB pages
+fn limits(memory: &MemoryType) -> String {
+    let maximum = match memory.maximum {
+        Some(maximum) => format!("{} pages", maximum.0),
+        None => "unbounded".to_string(),
+    };
+    let shared = if memory.shared { ", shared" } else { "" };
+    format!("min {} pages, max {}{}", memory.minimum.0, maximum, shared)
+}
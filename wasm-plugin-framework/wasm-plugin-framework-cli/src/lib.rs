@@ -0,0 +1,56 @@
+//! The commands of the `wasm-plugin` tool, which is also installed as the `cargo wasm-plugin` subcommand
+
+mod build;
+mod check;
+mod inspect;
+mod replay;
+mod sign;
+mod strip;
+
+use std::process;
+
+const USAGE: &str = "Usage:
+    wasm-plugin inspect <plugin.wasm|plugin.wplugin> [--api <name> <version> [<function>...]]
+    cargo wasm-plugin build [--manifest-path <Cargo.toml>] [--release] [--target <triple>] [--out-dir <dir>] [--strip] [--assets <dir>]
+    wasm-plugin keygen <name>
+    wasm-plugin sign <plugin.wasm|plugin.wplugin> --key <name.key>
+    wasm-plugin verify <plugin.wasm|plugin.wplugin> [--key <name.pub>]... [--sha256 <hash>]...
+    wasm-plugin replay <plugin.wasm|plugin.wplugin> <recording>
+
+Commands:
+    inspect    Prints the metadata, API functions, imports, memories and custom sections of a plugin
+               (and the manifest and assets of a package).
+               With --api, also checks the plugin can be loaded by the loader of that API,
+               failing if it can't
+    build      Builds the plugin crate for wasm32-wasi (or --target), checks it implements its APIs,
+               and writes it to the output directory (target/plugins by default) as <plugin>-<version>.wasm.
+               With --strip, removes the exports which aren't used by the framework.
+               With --assets, writes a <plugin>-<version>.wplugin package with the files of the directory as assets
+    keygen     Writes a new Ed25519 key pair to <name>.key (secret) and <name>.pub (public)
+    sign       Signs the module with the secret key, into <plugin.wasm>.sig, or the package contents
+               (manifest, module and assets) into the package, and prints their SHA-256 hash for pinning
+    verify     Checks the module or package is signed by one of the keys or has one of the hashes,
+               like an engine with that TrustPolicy does
+    replay     Makes the calls of a recording (written by a CallRecorder) again on the plugin,
+               printing the ones which give a different result, and failing if there are any";
+
+/// Runs the command given by the arguments (without the program name), exiting with an error if it fails
+pub fn main(args: &[String]) {
+    let result = match args.first().map(String::as_str) {
+        Some("inspect") => inspect::run(&args[1..]),
+        Some("build") => build::run(&args[1..]),
+        Some("keygen") => sign::keygen(&args[1..]),
+        Some("sign") => sign::sign(&args[1..]),
+        Some("verify") => sign::verify(&args[1..]),
+        Some("replay") => replay::run(&args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("expected a command\n\n{}", USAGE)),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
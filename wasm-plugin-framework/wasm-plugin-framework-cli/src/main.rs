@@ -0,0 +1,6 @@
+use std::env;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    wasm_plugin_framework_cli::main(&args);
+}
@@ -0,0 +1,70 @@
+use std::fs;
+
+use wasm_plugin_framework::{read_recording, replay, CompiledPlugin, Engine, PluginPackage};
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let (path, recording) = match args {
+        [path, recording] => (path, recording),
+        _ => return Err("expected replay <plugin.wasm|plugin.wplugin> <recording>".to_string()),
+    };
+
+    let bytes = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    let engine = Engine::new();
+    let compiled = if bytes.starts_with(b"\0asm") {
+        CompiledPlugin::verified(&engine, &bytes, None)
+    } else {
+        let package = PluginPackage::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+        CompiledPlugin::verified_from_package(&engine, &package)
+    }
+    .map_err(|e| format!("{}: {}", path, e))?;
+    let calls = read_recording(recording).map_err(|e| format!("couldn't read the recording {}: {}", recording, e))?;
+
+    let mismatches = replay(&compiled, &calls);
+    for mismatch in &mismatches {
+        let call = &mismatch.call;
+        println!(
+            "Call {} to {} of the API {} {} (recorded from {} {}):",
+            mismatch.index, call.function, call.api_name, call.api_version, call.plugin, call.plugin_version
+        );
+        println!("    recorded: {}", describe(&call.result));
+        println!("    replayed: {}", describe(&mismatch.replayed));
+    }
+    println!(
+        "{} of the {} calls gave the same result with {} {}",
+        calls.len() - mismatches.len(),
+        calls.len(),
+        compiled.plugin_name(),
+        compiled.info().version
+    );
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} calls gave a different result", mismatches.len()))
+    }
+}
+
+/// The encoded result of a call, in hex, or the error it failed with
+fn describe(result: &Result<Vec<u8>, String>) -> String {
+    match result {
+        Ok(bytes) => format!("{} bytes: {}", bytes.len(), hex::encode(bytes)),
+        Err(e) => format!("failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_on_invalid_plugins_instead_of_panicking() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording = dir.path().join("calls.rec").to_string_lossy().into_owned();
+        fs::write(&recording, b"").unwrap();
+        for (name, bytes) in [("invalid.wasm", &b"\0asm\x01\0\0\0\xff"[..]), ("invalid.wplugin", b"not a package")].iter() {
+            let path = dir.path().join(name).to_string_lossy().into_owned();
+            fs::write(&path, bytes).unwrap();
+            let error = run(&[path.clone(), recording.clone()]).unwrap_err();
+            assert!(error.starts_with(&path), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+};
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use wasm_plugin_framework::{module_hash, PluginPackage, TrustPolicy};
+
+/// Writes a new key pair to `<name>.key` (the secret key) and `<name>.pub` (the public key), as hex
+pub fn keygen(args: &[String]) -> Result<(), String> {
+    let name = match args {
+        [name] => name,
+        _ => return Err("expected keygen <name>".to_string()),
+    };
+    let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+    let secret_path = format!("{}.key", name);
+    let public_path = format!("{}.pub", name);
+    for path in [&secret_path, &public_path].iter() {
+        if Path::new(path).exists() {
+            return Err(format!("{} already exists", path));
+        }
+    }
+    // Only the owner can read the secret key
+    create_new(&secret_path, &hex::encode(keypair.secret.as_bytes()), 0o600)?;
+    create_new(&public_path, &hex::encode(keypair.public.as_bytes()), 0o644)?;
+    println!("Public key: {}", hex::encode(keypair.public.as_bytes()));
+    Ok(())
+}
+
+/// Writes a new file, failing if it exists (even if it was created since it was checked), with these permissions on unix
+fn create_new(path: &str, contents: &str, mode: u32) -> Result<(), String> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| format!("couldn't write {}: {}", path, e))
+}
+
+/// Signs a module, writing the signature to `<plugin.wasm>.sig`, or signs the contents of a package (its manifest, module and assets),
+/// writing the signature into the package
+pub fn sign(args: &[String]) -> Result<(), String> {
+    let (path, key_path) = match args {
+        [path, flag, key] if flag == "--key" => (path, key),
+        _ => return Err("expected sign <plugin.wasm|plugin.wplugin> --key <name.key>".to_string()),
+    };
+    let keypair = read_keypair(key_path)?;
+    let bytes = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    if bytes.starts_with(b"\0asm") {
+        let signature = keypair.sign(&bytes).to_bytes();
+        let signature_path = format!("{}.sig", path);
+        fs::write(&signature_path, &signature[..]).map_err(|e| format!("couldn't write {}: {}", signature_path, e))?;
+        println!("Signed {} into {}", path, signature_path);
+        println!("SHA-256: {}", module_hash(&bytes));
+    } else {
+        let package = PluginPackage::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+        let signature = keypair.sign(&package.signed_contents()).to_bytes();
+        let hash = module_hash(&package.signed_contents());
+        package
+            .with_signature(signature.to_vec())
+            .write(path)
+            .map_err(|e| format!("couldn't write {}: {}", path, e))?;
+        println!("Signed the contents of {}", path);
+        println!("SHA-256: {}", hash);
+    }
+    Ok(())
+}
+
+/// Checks a module (with its `.sig` file if there is one) or a package against the given public keys and pinned hashes,
+/// like an engine with that trust policy does
+pub fn verify(args: &[String]) -> Result<(), String> {
+    let (path, args) = args
+        .split_first()
+        .ok_or_else(|| "expected verify <plugin.wasm|plugin.wplugin> [--key <name.pub>]... [--sha256 <hash>]...".to_string())?;
+    let mut policy = TrustPolicy::new();
+    for pair in args.chunks(2) {
+        policy = match pair {
+            [flag, key] if flag == "--key" => {
+                let key = fs::read_to_string(key).map_err(|e| format!("couldn't read {}: {}", key, e))?;
+                policy.with_public_key(&key)
+            }
+            [flag, hash] if flag == "--sha256" => policy.with_pinned_hash(hash),
+            _ => return Err(format!("unexpected arguments {}", pair.join(" "))),
+        }
+        .map_err(|e| e.to_string())?;
+    }
+
+    let bytes = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    let result = if bytes.starts_with(b"\0asm") {
+        let signature = fs::read(format!("{}.sig", path)).ok();
+        policy.verify(&bytes, signature.as_deref())
+    } else {
+        let package = PluginPackage::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+        policy.verify(&package.signed_contents(), package.signature())
+    };
+    result.map_err(|e| e.to_string())?;
+    println!("{} is trusted", path);
+    Ok(())
+}
+
+fn read_keypair(path: &str) -> Result<Keypair, String> {
+    let hex = fs::read_to_string(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    let secret = hex::decode(hex.trim())
+        .ok()
+        .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| format!("{} isn't a secret key made by keygen", path))?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keygen_writes_a_private_secret_key_and_never_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = [dir.path().join("test").to_string_lossy().into_owned()];
+        let name = &args[0];
+        keygen(&args).unwrap();
+        let keypair = read_keypair(&format!("{}.key", name)).unwrap();
+        let public = fs::read_to_string(format!("{}.pub", name)).unwrap();
+        assert_eq!(public, hex::encode(keypair.public.as_bytes()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(format!("{}.key", name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+        assert!(keygen(&args).is_err());
+
+        // An existing public key isn't overwritten either
+        fs::remove_file(format!("{}.key", name)).unwrap();
+        assert!(keygen(&args).is_err());
+        assert_eq!(fs::read_to_string(format!("{}.pub", name)).unwrap(), public);
+        assert!(!Path::new(&format!("{}.key", name)).exists());
+    }
+}
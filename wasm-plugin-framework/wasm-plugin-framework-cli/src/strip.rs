@@ -0,0 +1,106 @@
+use wasm_plugin_framework::leb128;
+
+const SECTION_EXPORT: u8 = 7;
+
+/// The exports the framework uses: the ABI functions, the memory, the API functions and metadata (all prefixed with `__wpf_`),
+/// and the WASI entry points
+pub(crate) fn used_by_framework(name: &str) -> bool {
+    matches!(name, "memory" | "allocate_buffer" | "free_buffer" | "_start" | "_initialize") || name.starts_with("__wpf_")
+}
+
+/// Removes the exports for which `keep` is false, leaving the rest of the module as is.
+/// The functions which were only reachable from the removed exports are left for an optimizer like wasm-opt to remove
+pub(crate) fn strip_exports<F: Fn(&str) -> bool>(bytes: &[u8], keep: F) -> Result<(Vec<u8>, Vec<String>), String> {
+    let error = |message: &str| format!("couldn't strip the exports: {}", message);
+    let mut rest = match bytes {
+        [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, rest @ ..] => rest,
+        _ => return Err(error("not a wasm module")),
+    };
+    let mut out = bytes[..8].to_vec();
+    let mut removed = Vec::new();
+    while !rest.is_empty() {
+        let id = rest[0];
+        rest = &rest[1..];
+        let len = leb128::read(&mut rest).ok_or_else(|| error("invalid section size"))? as usize;
+        if rest.len() < len {
+            return Err(error("section extends past the end of the module"));
+        }
+        let (section, after) = rest.split_at(len);
+        rest = after;
+        if id != SECTION_EXPORT {
+            out.push(id);
+            leb128::write(&mut out, len as u32);
+            out.extend_from_slice(section);
+            continue;
+        }
+
+        // Each export is its name, kind and index, the kept ones are copied as they are
+        let mut section = section;
+        let count = leb128::read(&mut section).ok_or_else(|| error("invalid export section"))?;
+        let mut kept = 0;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let start = section;
+            let name_len = leb128::read(&mut section).ok_or_else(|| error("invalid export name"))? as usize;
+            if section.len() < name_len + 1 {
+                return Err(error("invalid export name"));
+            }
+            let name = std::str::from_utf8(&section[..name_len]).map_err(|_| error("invalid export name"))?;
+            section = &section[name_len + 1..];
+            leb128::read(&mut section).ok_or_else(|| error("invalid export index"))?;
+            if keep(name) {
+                entries.extend_from_slice(&start[..start.len() - section.len()]);
+                kept += 1;
+            } else {
+                removed.push(name.to_string());
+            }
+        }
+        let mut exports = Vec::new();
+        leb128::write(&mut exports, kept);
+        exports.extend(entries);
+        out.push(id);
+        leb128::write(&mut out, exports.len() as u32);
+        out.extend(exports);
+    }
+    Ok((out, removed))
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_plugin_framework::wasmer::wasmparser;
+
+    use super::*;
+
+    #[test]
+    fn strips_the_exports_not_used_by_the_framework() {
+        let module = wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1)
+                (func $f (result i32) (i32.const 1))
+                (export "__wpf__api__1_2e0_2e0__fn_f" (func $f))
+                (export "helper" (func $f))
+                (export "__data_end" (global 0))
+                (global i32 (i32.const 0)))"#,
+        )
+        .unwrap();
+        let (stripped, removed) = strip_exports(&module, used_by_framework).unwrap();
+        assert_eq!(removed, ["helper", "__data_end"]);
+        wasmparser::validate(&stripped).unwrap();
+
+        let mut exports = Vec::new();
+        for payload in wasmparser::Parser::new(0).parse_all(&stripped) {
+            if let wasmparser::Payload::ExportSection(reader) = payload.unwrap() {
+                for export in reader {
+                    exports.push(export.unwrap().field.to_string());
+                }
+            }
+        }
+        assert_eq!(exports, ["memory", "__wpf__api__1_2e0_2e0__fn_f"]);
+    }
+
+    #[test]
+    fn rejects_non_wasm_bytes() {
+        assert!(strip_exports(b"not wasm", used_by_framework).is_err());
+        assert!(strip_exports(&[0, 0x61, 0x73, 0x6d, 1, 0, 0, 0, 7, 10, 1], used_by_framework).is_err());
+    }
+}
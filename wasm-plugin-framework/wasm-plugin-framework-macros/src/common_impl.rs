@@ -1,35 +1,591 @@
-use proc_macro2::TokenStream;
-use quote::{quote, ToTokens};
-use syn::{FnArg, Ident, LitStr, ReturnType, Token, TraitItemMethod, parse::Parse};
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote, ToTokens};
+use crate::{metadata, validate};
+use syn::{
+    parse::Parse, Attribute, AttributeArgs, FnArg, Ident, ItemTrait, Lit, LitStr, Meta, NestedMeta, ReturnType, Token,
+    TraitItem, TraitItemMethod,
+};
 
 pub struct CommonPluginImplementation {
     api_name: LitStr,
     api_version: LitStr,
     loader_name: Ident,
+    /// The host-facing trait, implemented by the loader, pool and shared types
+    host_trait: Ident,
+    /// The trait the API was declared with, when using the plugin_api attribute
+    api_trait: Option<ItemTrait>,
     fns: Vec<TraitItemMethod>,
 }
 
 impl Parse for CommonPluginImplementation {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let api_name = input.parse()?;
-        let _: Token![,] = input.parse()?;
-        let api_version = input.parse()?;
-        let _: Token![,] = input.parse()?;
-        let loader_name = input.parse()?;
-        let _: Token![,] = input.parse()?;
-        let mut fns = Vec::new();
+        let api_name = input
+            .parse()
+            .map_err(|e| syn::Error::new(e.span(), "Expected the API name, as a string literal"))?;
+        expect_comma(input, "the API name")?;
+        let api_version = input
+            .parse()
+            .map_err(|e| syn::Error::new(e.span(), "Expected the API version, as a string literal"))?;
+        validate::validate_version(&api_version)?;
+        expect_comma(input, "the API version")?;
+        let loader_name = input
+            .parse()
+            .map_err(|e| syn::Error::new(e.span(), "Expected the name of the loader struct"))?;
+        expect_comma(input, "the loader name")?;
+        let mut fns: Vec<TraitItemMethod> = Vec::new();
         while !input.is_empty() {
             fns.push(input.parse()?);
         }
+        validate::validate_fns(fns.iter().map(|f| &f.sig))?;
         Ok(Self {
             api_name,
             api_version,
+            host_trait: format_ident!("{}Host", loader_name),
             loader_name,
+            api_trait: None,
             fns,
         })
     }
 }
 
+/// Parses the `,` after an argument of the macro
+pub fn expect_comma(input: syn::parse::ParseStream, after: &str) -> syn::Result<()> {
+    input
+        .parse::<Token![,]>()
+        .map(|_| ())
+        .map_err(|e| syn::Error::new(e.span(), format!("Expected `,` after {}", after)))
+}
+
+impl CommonPluginImplementation {
+    /// Takes the API from a trait, with the name, version, loader and host trait given as the attribute arguments.
+    /// The loader defaults to `<Trait>Loader`, and the host trait to `<Trait>Host`
+    pub fn from_trait(args: AttributeArgs, api_trait: ItemTrait) -> syn::Result<Self> {
+        let mut api_name = None;
+        let mut api_version = None;
+        let mut loader_name = None;
+        let mut host_trait = None;
+        for arg in args {
+            match arg {
+                NestedMeta::Meta(Meta::NameValue(nv)) => {
+                    let s = match &nv.lit {
+                        Lit::Str(s) => s.clone(),
+                        lit => return Err(syn::Error::new_spanned(lit, "Expected a string literal")),
+                    };
+                    if nv.path.is_ident("name") {
+                        api_name = Some(s);
+                    } else if nv.path.is_ident("version") {
+                        api_version = Some(s);
+                    } else if nv.path.is_ident("loader") {
+                        loader_name = Some(s.parse()?);
+                    } else if nv.path.is_ident("host") {
+                        host_trait = Some(s.parse()?);
+                    } else {
+                        return Err(syn::Error::new_spanned(
+                            nv.path,
+                            "Unknown argument, expected one of `name`, `version`, `loader` or `host`",
+                        ));
+                    }
+                }
+                arg => {
+                    return Err(syn::Error::new_spanned(
+                        arg,
+                        "Expected an argument like `name = \"API NAME\"`",
+                    ))
+                }
+            }
+        }
+        // Proc macros run with the environment cargo sets for the crate being compiled
+        let api_version = api_version.unwrap_or_else(|| {
+            LitStr::new(&std::env::var("CARGO_PKG_VERSION").unwrap_or_default(), Span::call_site())
+        });
+        validate::validate_version(&api_version)?;
+        if !api_trait.generics.params.is_empty() {
+            return Err(syn::Error::new_spanned(&api_trait.generics, "API traits can't be generic"));
+        }
+        let api_name = api_name.ok_or_else(|| {
+            syn::Error::new_spanned(&api_trait.ident, "Missing the API name: #[plugin_api(name = \"API NAME\")]")
+        })?;
+        let loader_name = loader_name.unwrap_or_else(|| format_ident!("{}Loader", api_trait.ident));
+        let host_trait = host_trait.unwrap_or_else(|| format_ident!("{}Host", api_trait.ident));
+        let fns = api_trait
+            .items
+            .iter()
+            .map(|item| match item {
+                TraitItem::Method(m) => Ok(m.clone()),
+                item => Err(syn::Error::new_spanned(item, "API traits can only contain functions")),
+            })
+            .collect::<syn::Result<Vec<_>>>()?;
+        validate::validate_fns(fns.iter().map(|f| &f.sig))?;
+        Ok(Self {
+            api_name,
+            api_version,
+            loader_name,
+            host_trait,
+            api_trait: Some(api_trait),
+            fns,
+        })
+    }
+}
+
+/// The name of the macro_rules macro the common library exports to generate the plugin exports of the API declared by this trait.
+/// The plugin side only knows the path of the trait, so it can't include the API name and version,
+/// and the API traits of a crate need distinct names
+pub fn exports_macro_name(api_trait: &Ident) -> Ident {
+    format_ident!("__wpf_exports_{}", api_trait)
+}
+
+/// Escapes a part of the symbols, keeping the ASCII letters and digits and turning any other byte into `_` and its hex value,
+/// so that the parts, separated by `__`, can't run into each other. Must match the escaping of `PluginApi::symbol`
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| if b.is_ascii_alphanumeric() { (b as char).to_string() } else { format!("_{:02x}", b) })
+        .collect()
+}
+
+/// The attributes of an API function which are kept on the loader methods (docs and deprecation)
+fn forwarded_attrs(attrs: &[Attribute]) -> impl Iterator<Item = &Attribute> {
+    attrs
+        .iter()
+        .filter(|a| a.path.is_ident("doc") || a.path.is_ident("deprecated"))
+}
+
+impl CommonPluginImplementation {
+    /// API methods which call the same method on the loader given by `instance`
+    fn delegating_methods(&self, instance: TokenStream) -> Vec<TokenStream> {
+        self.fns
+            .iter()
+            .map(|x| {
+                let sig = &x.sig;
+                let unsafety = &sig.unsafety;
+                let ident = &sig.ident;
+                let args = &sig.inputs;
+                let output = &sig.output;
+                let arg_names = args.iter().filter_map(|x| match x {
+                    FnArg::Receiver(_) => None,
+                    FnArg::Typed(p) => Some(&p.pat),
+                });
+                let attrs = forwarded_attrs(&x.attrs);
+                quote! {
+                    #(#attrs)*
+                    #[allow(deprecated)]
+                    pub #unsafety fn #ident(&self, #args) #output {
+                        #instance.#ident(#(#arg_names),*)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Async variants (`<name>_async`) of the API methods, which move `handle` (a clone of the pool or shared handle,
+    /// or another loader bound to the same instance) to tokio's blocking pool, and call the method on the loader given by `instance` there
+    fn async_delegating_methods(&self, handle: TokenStream, instance: TokenStream) -> Vec<TokenStream> {
+        if !cfg!(feature = "async") {
+            return Vec::new();
+        }
+        self.fns
+            .iter()
+            .map(|x| {
+                let sig = &x.sig;
+                let unsafety = &sig.unsafety;
+                let ident = &sig.ident;
+                let async_ident = format_ident!("{}_async", ident);
+                let args = &sig.inputs;
+                let output = &sig.output;
+                let arg_names = args.iter().filter_map(|x| match x {
+                    FnArg::Receiver(_) => None,
+                    FnArg::Typed(p) => Some(&p.pat),
+                });
+                let attrs = forwarded_attrs(&x.attrs);
+                quote! {
+                    #(#attrs)*
+                    #[allow(deprecated)]
+                    pub #unsafety async fn #async_ident(&self, #args) #output {
+                        let handle = #handle;
+                        ::wasm_plugin_framework::spawn_call(move || #instance.#ident(#(#arg_names),*)).await
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+impl CommonPluginImplementation {
+    /// The host-facing trait, so that wasm plugins, native implementations and mocks can be used through the same interface
+    fn host_trait(&self) -> TokenStream {
+        let host_trait = &self.host_trait;
+        let api_name = self.api_name.value();
+        let methods = self.fns.iter().map(|x| {
+            let sig = &x.sig;
+            let unsafety = &sig.unsafety;
+            let ident = &sig.ident;
+            let args = &sig.inputs;
+            let output = &sig.output;
+            let attrs = forwarded_attrs(&x.attrs);
+            quote! {
+                #(#attrs)*
+                #unsafety fn #ident(&self, #args) #output;
+            }
+        });
+        let doc = format!(
+            "The {} API, as seen by the host. It is implemented by the loader of the wasm plugins, and can be implemented natively",
+            api_name
+        );
+        quote! {
+            #[doc = #doc]
+            pub trait #host_trait {
+                #(#methods)*
+            }
+        }
+    }
+
+    /// The name the native and mock types are derived from, the one of the host trait without `Host`
+    fn base_name(&self) -> String {
+        self.host_trait.to_string().trim_end_matches("Host").to_string()
+    }
+
+    /// A mock of the API, whose responses are programmed with closures, and which records the calls made to it
+    fn mock(&self) -> TokenStream {
+        let host_trait = &self.host_trait;
+        let mock_name = format_ident!("{}Mock", self.base_name());
+        let mut fields = Vec::new();
+        let mut field_inits = Vec::new();
+        let mut methods = Vec::new();
+        let mut trait_methods = Vec::new();
+        for x in &self.fns {
+            let sig = &x.sig;
+            let unsafety = &sig.unsafety;
+            let ident = &sig.ident;
+            let name = ident.to_string();
+            let args = &sig.inputs;
+            let output = &sig.output;
+            let (arg_names, arg_types): (Vec<_>, Vec<_>) = args
+                .iter()
+                .filter_map(|x| match x {
+                    FnArg::Typed(p) => Some((&p.pat, &p.ty)),
+                    FnArg::Receiver(_) => None,
+                })
+                .unzip();
+            let on = format_ident!("on_{}", ident);
+            let calls = format_ident!("{}_calls", ident);
+            let call_count = format_ident!("{}_call_count", ident);
+            let assert_called = format_ident!("assert_{}_called", ident);
+            let on_doc = format!("Programs the response of `{}`, replacing the previous one", name);
+            let calls_doc = format!("The arguments of the calls made to `{}`, in order", name);
+            let assert_called_doc = format!("Panics if `{}` wasn't called exactly `times` times", name);
+            let unprogrammed = format!("No response programmed for `{}`, use `{}`", name, on);
+            let attrs = forwarded_attrs(&x.attrs);
+            fields.push(quote! {
+                #ident: ::std::sync::Mutex<Option<::std::sync::Arc<dyn (Fn(#(#arg_types),*) #output) + Send + Sync>>>
+            });
+            field_inits.push(quote!(#ident: ::std::sync::Mutex::new(None)));
+            methods.push(quote! {
+                #[doc = #on_doc]
+                pub fn #on<F>(self, f: F) -> Self
+                where
+                    F: Fn(#(#arg_types),*) #output + Send + Sync + 'static,
+                {
+                    *self.#ident.lock().unwrap() = Some(::std::sync::Arc::new(f));
+                    self
+                }
+
+                #[doc = #calls_doc]
+                pub fn #calls(&self) -> Vec<(#(#arg_types,)*)> {
+                    self.calls
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|(function, _)| *function == #name)
+                        .map(|(_, args)| ::wasm_plugin_framework::testing::decode(args))
+                        .collect()
+                }
+
+                pub fn #call_count(&self) -> usize {
+                    self.calls.lock().unwrap().iter().filter(|(function, _)| *function == #name).count()
+                }
+
+                #[doc = #assert_called_doc]
+                pub fn #assert_called(&self, times: usize) {
+                    let count = self.#call_count();
+                    assert_eq!(count, times, "Expected {} calls to `{}`, but it was called {} times", times, #name, count);
+                }
+
+                #(#attrs)*
+                #[allow(deprecated)]
+                pub #unsafety fn #ident(&self, #args) #output {
+                    self.calls
+                        .lock()
+                        .unwrap()
+                        .push((#name, ::wasm_plugin_framework::testing::encode(&(#(&#arg_names,)*))));
+                    // Called without the lock, so that it can call the mock, and concurrent calls don't wait for each other
+                    let f = self.#ident.lock().unwrap().clone().unwrap_or_else(|| panic!(#unprogrammed));
+                    f(#(#arg_names),*)
+                }
+            });
+            trait_methods.push(quote! {
+                #[allow(deprecated)]
+                #unsafety fn #ident(&self, #args) #output {
+                    #mock_name::#ident(self, #(#arg_names),*)
+                }
+            });
+        }
+        quote! {
+            #[cfg(not(target_arch = "wasm32"))]
+            mod mock {
+                use super::*;
+
+                /// A mock of the API, to test the host code using it without a plugin.
+                /// The responses are programmed with the `on_<function>` methods, and the calls are recorded,
+                /// which can be checked with the `<function>_calls` and `assert_<function>_called` methods.
+                /// The responses are called without holding any lock, they can be called concurrently and can call the mock
+                pub struct #mock_name {
+                    calls: ::std::sync::Mutex<Vec<(&'static str, Vec<u8>)>>,
+                    #(#fields,)*
+                }
+
+                impl #mock_name {
+                    pub fn new() -> Self {
+                        Self {
+                            calls: ::std::sync::Mutex::new(Vec::new()),
+                            #(#field_inits,)*
+                        }
+                    }
+
+                    /// The names of the functions called, in order
+                    pub fn calls(&self) -> Vec<&'static str> {
+                        self.calls.lock().unwrap().iter().map(|(function, _)| *function).collect()
+                    }
+
+                    /// Panics if any function was called
+                    pub fn assert_not_called(&self) {
+                        let calls = self.calls();
+                        assert!(calls.is_empty(), "Expected no calls, but got {:?}", calls);
+                    }
+
+                    #(#methods)*
+                }
+
+                impl Default for #mock_name {
+                    fn default() -> Self {
+                        Self::new()
+                    }
+                }
+
+                impl super::#host_trait for #mock_name {
+                    #(#trait_methods)*
+                }
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            pub use mock::#mock_name;
+        }
+    }
+
+    /// A native implementation of the host trait for any implementation of the API trait, which passes the arguments and results
+    /// through the plugin ABI encoding, so that the plugins can be tested natively
+    fn native_plugin(&self) -> TokenStream {
+        let host_trait = &self.host_trait;
+        let native_name = format_ident!("{}Native", self.base_name());
+        let methods = self.fns.iter().map(|x| {
+            let sig = &x.sig;
+            let unsafety = &sig.unsafety;
+            let ident = &sig.ident;
+            let args = &sig.inputs;
+            let output = &sig.output;
+            let arg_names: Vec<_> = args
+                .iter()
+                .filter_map(|x| match x {
+                    FnArg::Receiver(_) => None,
+                    FnArg::Typed(p) => Some(&p.pat),
+                })
+                .collect();
+            let call = quote!(<T as super::metadata::Plugin>::#ident(#(#arg_names),*));
+            let body = match output {
+                ReturnType::Default => quote!(#call;),
+                ReturnType::Type(_, _) => quote!(::wasm_plugin_framework::testing::round_trip(&#call)),
+            };
+            quote! {
+                #[allow(deprecated)]
+                #unsafety fn #ident(&self, #args) #output {
+                    #(let #arg_names = ::wasm_plugin_framework::testing::round_trip(&#arg_names);)*
+                    #body
+                }
+            }
+        });
+        quote! {
+            #[cfg(not(target_arch = "wasm32"))]
+            mod native {
+                use super::*;
+
+                /// A plugin implementation called in-process, with the arguments and results going through the plugin ABI encoding like with a wasm plugin.
+                /// It lets `cargo test` on the plugin crate test it without a wasm toolchain
+                pub struct #native_name<T>(::std::marker::PhantomData<fn() -> T>);
+
+                impl<T> #native_name<T> {
+                    pub fn new() -> Self {
+                        Self(::std::marker::PhantomData)
+                    }
+                }
+
+                impl<T> Default for #native_name<T> {
+                    fn default() -> Self {
+                        Self::new()
+                    }
+                }
+
+                impl<T: super::metadata::Plugin> super::#host_trait for #native_name<T> {
+                    #(#methods)*
+                }
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            pub use native::#native_name;
+        }
+    }
+
+    /// The implementation of the host trait for one of the generated types, calling its inherent methods
+    fn host_trait_impl(&self, ty: &Ident) -> TokenStream {
+        let host_trait = &self.host_trait;
+        let methods = self.fns.iter().map(|x| {
+            let sig = &x.sig;
+            let unsafety = &sig.unsafety;
+            let ident = &sig.ident;
+            let args = &sig.inputs;
+            let output = &sig.output;
+            let arg_names = args.iter().filter_map(|x| match x {
+                FnArg::Receiver(_) => None,
+                FnArg::Typed(p) => Some(&p.pat),
+            });
+            quote! {
+                #[allow(deprecated)]
+                #unsafety fn #ident(&self, #args) #output {
+                    #ty::#ident(self, #(#arg_names),*)
+                }
+            }
+        });
+        quote! {
+            impl super::#host_trait for #ty {
+                #(#methods)*
+            }
+        }
+    }
+}
+
+impl CommonPluginImplementation {
+    /// The prefix of the symbols the plugins export the API functions with,
+    /// so that they don't collide with the framework exports, other APIs, or other versions of the same API.
+    /// The host computes the same symbols to call the plugins from other plugins (`PluginApi::symbol`)
+    fn symbol_prefix(&self) -> String {
+        format!("__wpf__{}__{}__", escape(&self.api_name.value()), escape(&self.api_version.value()))
+    }
+
+    /// The symbol an API function is exported with
+    fn fn_symbol(&self, ident: &Ident) -> LitStr {
+        LitStr::new(&format!("{}fn_{}", self.symbol_prefix(), escape(&ident.to_string())), ident.span())
+    }
+
+    /// A macro_rules macro which generates the plugin exports, called by the plugin macros with the type implementing the API,
+    /// the path of the API trait where it's called, and the null terminated plugin name.
+    /// Besides the API functions, it exports the API name and version under the API's prefix,
+    /// so that a plugin can implement many APIs, and the host can list them
+    fn exports_macro(&self) -> TokenStream {
+        let trait_ident = match &self.api_trait {
+            Some(api_trait) => api_trait.ident.clone(),
+            None => format_ident!("Plugin"),
+        };
+        // Exported at the crate root, where two APIs with the same trait name fail with a duplicate definition of the macro
+        let macro_name = exports_macro_name(&trait_ident);
+        let shims = self.fns.iter().map(|x| {
+            let sig = &x.sig;
+            let ident = &sig.ident;
+            let symbol = self.fn_symbol(ident);
+            let shim_ident = format_ident!("{}", symbol.value());
+            // Receivers are rejected when validating the signatures
+            let (abi_args, abi_args_conversions): (Vec<_>, Vec<_>) = sig
+                .inputs
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| matches!(x, FnArg::Typed(_)))
+                .map(|(i, _)| {
+                    let argname = format_ident!("arg{}", i);
+                    (
+                        quote!(#argname: u32),
+                        quote!($crate::wasm_plugin_framework::abi::from_abi(#argname)),
+                    )
+                })
+                .unzip();
+            let calling_code = quote! {
+                <$implementor as $api>::#ident(#(#abi_args_conversions),*)
+            };
+            let (body, return_t) = match &sig.output {
+                ReturnType::Default => (quote!(#calling_code;), quote!()),
+                ReturnType::Type(_, _) => (
+                    quote!($crate::wasm_plugin_framework::abi::into_abi(&#calling_code)),
+                    quote!(-> u32),
+                ),
+            };
+            quote! {
+                #[cfg(target_arch = "wasm32")]
+                #[export_name = #symbol]
+                #[allow(deprecated, non_snake_case)]
+                pub extern "C" fn #shim_ident(#(#abi_args),*) #return_t {#body}
+            }
+        });
+        let table_symbol = format!("{}exports", self.symbol_prefix());
+        let table_ident = format_ident!("{}", table_symbol);
+        let metadata_fn = |suffix: &str, value: TokenStream| {
+            let symbol = format!("{}{}", self.symbol_prefix(), suffix);
+            let ident = format_ident!("{}", symbol);
+            quote! {
+                #[cfg(target_arch = "wasm32")]
+                #[export_name = #symbol]
+                #[allow(non_snake_case)]
+                pub extern "C" fn #ident() -> *const u8 {
+                    #value.as_ptr()
+                }
+            }
+        };
+        let api_name_c = LitStr::new(&format!("{}\0", self.api_name.value()), self.api_name.span());
+        let api_version_c = LitStr::new(&format!("{}\0", self.api_version.value()), self.api_version.span());
+        let api_name_fn = metadata_fn("api_name", quote!(#api_name_c));
+        let api_version_fn = metadata_fn("api_version", quote!(#api_version_c));
+        let plugin_name_fn = metadata_fn("plugin_name", quote!($plugin_name));
+        let fn_names: Vec<String> = self.fns.iter().map(|x| x.sig.ident.to_string()).collect();
+        let metadata_record = metadata::api_record(
+            format_ident!("{}metadata", self.symbol_prefix()),
+            &self.api_name,
+            &self.api_version,
+            &fn_names,
+        );
+        let mut table = String::new();
+        for x in &self.fns {
+            table.push_str(&format!("{}={}\n", x.sig.ident, self.fn_symbol(&x.sig.ident).value()));
+        }
+        table.push('\0');
+        quote! {
+            #[doc(hidden)]
+            #[macro_export]
+            macro_rules! #macro_name {
+                ($implementor:ty, $api:path, $plugin_name:expr) => {
+                    #(#shims)*
+
+                    #api_name_fn
+                    #api_version_fn
+                    #plugin_name_fn
+                    #metadata_record
+
+                    /// The table of the API functions and the symbols they are exported with, as null terminated `name=symbol` lines
+                    #[cfg(target_arch = "wasm32")]
+                    #[export_name = #table_symbol]
+                    #[allow(non_snake_case)]
+                    pub extern "C" fn #table_ident() -> *const u8 {
+                        #table.as_ptr()
+                    }
+                };
+            }
+        }
+    }
+}
+
 impl ToTokens for CommonPluginImplementation {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let mut api_name_s = self.api_name.value();
@@ -47,38 +603,45 @@ impl ToTokens for CommonPluginImplementation {
             .iter()
             .cloned()
             .map(|x| {
+                let attrs: Vec<_> = forwarded_attrs(&x.attrs).cloned().collect();
                 let sig = x.sig;
                 let unsafety = sig.unsafety;
                 let fn_token = sig.fn_token;
                 let ident = sig.ident;
                 let args = sig.inputs;
-                let abi_args = args.clone().into_iter().map(|x| match x {
-                    FnArg::Receiver(_) => quote!(compile_error!(
-                        "Unexpected reciever (&self & co.) in arguments"
-                    )),
+                // Receivers are rejected when validating the signatures
+                let abi_args = args.clone().into_iter().filter_map(|x| match x {
+                    FnArg::Receiver(_) => None,
                     FnArg::Typed(p) => {
                         let pat = p.pat;
-                        quote! {(::wasm_plugin_framework::abi::into_abi(self, &#pat) as i32).into()}
+                        Some(quote! {::wasm_plugin_framework::abi::into_bytes(&#pat)})
                     }
                 });
                 let output = sig.output;
-                let abi_fn_name = LitStr::new(&ident.to_string(), ident.span());
+                let abi_fn_name = self.fn_symbol(&ident);
+                let fn_name = LitStr::new(&ident.to_string(), ident.span());
 
-                let calling_code = quote! {
-                    self.instance.exports.get_function(#abi_fn_name)
-                    .expect("Tried to load a non compliant plugin, which doesn't have the one of the API's required functions")
-                    .call(&[#(#abi_args),*]).expect("Unexpecteed error while calling API function")
+                let decode = match output {
+                    ReturnType::Default => quote!(|_| ()),
+                    ReturnType::Type(_, _) => quote!(::wasm_plugin_framework::abi::from_bytes),
                 };
-
-                let fn_body = match output {
-                    ReturnType::Default => quote! {
-                        #calling_code;
-                    },
-                    ReturnType::Type(_, _) => quote! {
-                        ::wasm_plugin_framework::abi::from_abi(self, #calling_code[0].unwrap_i32() as u32)
-                    }
+                // The compiled plugin encodes the arguments, copies them into the instance, and times, traces and records the call
+                let fn_body = quote! {
+                    self.compiled.call_function(
+                        self,
+                        &self.instance,
+                        &::wasm_plugin_framework::ApiFunction {
+                            api_name: super::metadata::API_NAME,
+                            api_version: super::metadata::API_VERSION,
+                            name: #fn_name,
+                            symbol: #abi_fn_name,
+                        },
+                        || vec![#(#abi_args),*],
+                        #decode,
+                    )
                 };
                 quote! {
+                    #(#attrs)*
                     pub #unsafety #fn_token #ident(&self, #args) #output {
                         #fn_body
                     }
@@ -86,9 +649,51 @@ impl ToTokens for CommonPluginImplementation {
             })
             .collect();
 
+        // The calls run on another thread, with another loader bound to the same instance, which the calls lock
+        let async_methods = self.async_delegating_methods(quote!(self.rebind()), quote!(handle));
+        let pool_name = format_ident!("{}Pool", loader_name);
+        let pool_methods = self.delegating_methods(quote!(self.pool.get()));
+        let pool_async_methods = self.async_delegating_methods(quote!(self.pool.clone()), quote!(handle.get()));
+        let shared_name = format_ident!("{}Shared", loader_name);
+        let shared_methods = self.delegating_methods(quote!(self.shared.lock()));
+        let shared_async_methods = self.async_delegating_methods(quote!(self.shared.clone()), quote!(handle.lock()));
+
         let fns = &self.fns;
+        let exports = self.fns.iter().map(|x| {
+            let name = LitStr::new(&x.sig.ident.to_string(), x.sig.ident.span());
+            let symbol = self.fn_symbol(&x.sig.ident);
+            quote!((#name, #symbol))
+        });
+        let exports_macro = self.exports_macro();
+        let host_trait = self.host_trait();
+        let native_plugin = self.native_plugin();
+        let mock = self.mock();
+        let loader_host_impl = self.host_trait_impl(loader_name);
+        let pool_host_impl = self.host_trait_impl(&pool_name);
+        let shared_host_impl = self.host_trait_impl(&shared_name);
+        let (api_trait, plugin_trait) = match &self.api_trait {
+            Some(api_trait) => {
+                let ident = &api_trait.ident;
+                (quote!(#api_trait), quote!(pub use super::#ident as Plugin;))
+            }
+            None => (
+                quote!(),
+                quote! {
+                    #[doc(hidden)]
+                    mod inner {
+                        use super::super::*;
+                        pub trait Plugin {
+                            #(#fns)*
+                        }
+                    }
+                    pub use inner::Plugin;
+                },
+            ),
+        };
 
         let r = quote! {
+            #api_trait
+
             /// Metadata generated from the common_plugin_impl macro. Contains the api name and version.
             pub mod metadata {
                 /// Null terminated version of the API name
@@ -97,110 +702,149 @@ impl ToTokens for CommonPluginImplementation {
                 /// Null terminated version of the API version
                 pub const API_VERSION_C: &'static str = #api_version_c;
                 pub const API_VERSION: &'static str = #api_version;
+                /// The API functions, with the symbols the plugins export them with
+                pub const EXPORTS: &[(&str, &str)] = &[#(#exports),*];
 
-
-                #[doc(hidden)]
-                mod inner {
-                    use super::super::*;
-                    pub trait Plugin {
-                        #(#fns)*
-                    }
-                }
-                pub use inner::Plugin;
+                #plugin_trait
             }
 
+            #exports_macro
+
+            #host_trait
+
+            #native_plugin
+
+            #mock
+
             #[cfg(not(target_arch = "wasm32"))]
             mod loader {
-                use ::std::path::Path;
-                use ::wasm_plugin_framework::wasmer::{imports, Extern, Instance, Memory, MemoryType, Module, Store, Value, ImportObject};
-                use ::wasm_plugin_framework::wasmer_wasi::WasiState;
+                use ::std::{cell::Cell, marker::PhantomData};
+                use ::wasm_plugin_framework::wasmer::Memory;
+                use ::wasm_plugin_framework::{CompiledPlugin, Engine, MetadataError, PluginInfo, PluginInstance, PluginMetadata};
                 use super::*;
 
+                /// A plugin instance.
+                ///
+                /// It is `Send`, so it can be moved into a worker thread, but not `Sync`, as API calls write to the guest memory through `&self`.
+                /// To call the same plugin from many threads, use a pool (many instances) or a shared handle (one instance behind a mutex).
+                /// The loaders bound to the same instance with `from_instance` can each be moved to another thread, the calls lock the instance.
                 pub struct #loader_name {
-                    store: Store,
-                    module: Module,
-                    import_object: ImportObject,
-                    pub instance: Instance,
+                    compiled: CompiledPlugin,
+                    pub instance: PluginInstance,
                     pub name: String,
-
+                    _not_sync: PhantomData<Cell<()>>,
                 }
 
                 impl #loader_name {
-                    pub fn new(bytes: &[u8]) ->Self {
-                        let store = Store::default();
-
-                        let module = Module::new(&store, bytes).expect("Error loading the WASM module based on the bytes");
-                        // The module doesn't import anything, so we create an empty import object.
-                        let mut wasi_env = WasiState::new("plugin")
-                            // .args(&["world"])
-                            // .env("KEY", "Value")
-                            .finalize().expect("Error finalizing the WASI environment");
-
-                        println!("Instantiating module with WASI imports...");
-                        // Then, we get the import object related to our WASI
-                        // and attach it to the Wasm instance.
-                        let import_object = wasi_env.import_object(&module).expect("Error creating the WASI import object based on the wasm module");
-                        let instance = Instance::new(&module, &import_object).expect("Error creating the WASM module instance");
-
-                        let m = instance.exports.get_memory("memory").expect("Expected a memory to be exported, are you sure this is a plugin?");
-
-                        let api_name: Vec<u8> = m
-                            .view()
-                            .iter()
-                            .skip(
-                                instance.exports.get_function("API_NAME").expect("Tried to load a non plugin, which doesn't have the API_NAME function").call(&[]).expect("Unexpected error when calling API_NAME")[0].unwrap_i32() as usize
-                            )
-                            .take_while(|x| {
-                                let v: u8 = x.get();
-                                v != 0
-                            })
-                            .map(|x| x.get())
-                            .collect();
-                        let api_name = String::from_utf8_lossy(&api_name).to_string();
-
-                        let api_version: Vec<u8> = m
-                            .view()
-                            .iter()
-                            .skip(
-                                instance.exports.get_function("API_VERSION").expect("Tried to load a non plugin, which doesn't have the API_VERSION function").call(&[]).expect("Unexpected error when calling API_VERSION")[0].unwrap_i32() as usize
-                            )
-                            .take_while(|x| {
-                                let v: u8 = x.get();
-                                v != 0
-                            })
-                            .map(|x| x.get())
-                            .collect();
-                        let api_version = String::from_utf8_lossy(&api_version).to_string();
-
-                        let plugin_name: Vec<u8> = m
-                            .view()
-                            .iter()
-                            .skip(
-                                instance.exports.get_function("PLUGIN_NAME").expect("Tried to load a non plugin, which doesn't have the PLUGIN_NAME function").call(&[]).expect("Unexpected error when calling PLUGIN_NAME")[0].unwrap_i32() as usize
-                            )
-                            .take_while(|x| {
-                                let v: u8 = x.get();
-                                v != 0
-                            })
-                            .map(|x| x.get())
-                            .collect();
-                        let plugin_name = String::from_utf8_lossy(&plugin_name).to_string();
-
-                        assert_eq!(super::metadata::API_NAME, api_name, "The plugin API name doesn't match the current API name");
-                        assert_eq!(super::metadata::API_VERSION, api_version, "The plugin API version doesn't match the current API version");
+                    pub fn new(bytes: &[u8]) -> Self {
+                        Self::new_with_engine(&Engine::default(), bytes)
+                    }
+
+                    /// Compiles and instantiates the plugin with an engine shared with other plugins
+                    pub fn new_with_engine(engine: &Engine, bytes: &[u8]) -> Self {
+                        Self::from_compiled(&Self::compile(engine, bytes))
+                    }
 
+                    /// Compiles the plugin and checks that it implements this API, so that it can be instantiated many times
+                    pub fn compile(engine: &Engine, bytes: &[u8]) -> CompiledPlugin {
+                        let compiled = CompiledPlugin::new(engine, bytes);
+                        Self::check_api(&compiled);
+                        compiled
+                    }
+
+                    /// Compiles and instantiates the plugin of a package, with its assets mapped into its WASI filesystem
+                    pub fn from_package(engine: &Engine, package: &::wasm_plugin_framework::PluginPackage) -> Self {
+                        let compiled = CompiledPlugin::from_package(engine, package);
+                        Self::check_api(&compiled);
+                        Self::from_compiled(&compiled)
+                    }
+
+                    /// Creates a new instance of an already compiled plugin
+                    pub fn from_compiled(compiled: &CompiledPlugin) -> Self {
+                        Self::from_instance(compiled, &compiled.instantiate())
+                    }
+
+                    /// Records the calls made through this loader to a file, which can be replayed against another build of the plugin
+                    pub fn with_recorder(self, recorder: ::wasm_plugin_framework::CallRecorder) -> Self {
                         Self {
-                            store,
-                            module,
-                            import_object,
-                            instance,
-                            name: plugin_name,
+                            compiled: self.compiled.with_recorder(recorder),
+                            ..self
                         }
                     }
 
+                    /// Binds to an existing instance of the plugin, eg. to call this API on the same instance as the other APIs the plugin provides.
+                    /// The loaders bound to the same instance share its memory, each call locks the instance until it returns
+                    pub fn from_instance(compiled: &CompiledPlugin, instance: &PluginInstance) -> Self {
+                        Self::check_api(compiled);
+                        Self {
+                            compiled: compiled.clone(),
+                            instance: instance.clone(),
+                            name: compiled.plugin_name().to_string(),
+                            _not_sync: PhantomData,
+                        }
+                    }
+
+                    /// Another loader bound to the same instance
+                    #[allow(dead_code)]
+                    fn rebind(&self) -> Self {
+                        Self::from_instance(&self.compiled, &self.instance)
+                    }
+
+                    fn check_api(compiled: &CompiledPlugin) {
+                        if let Err(e) = Self::check_compatible(compiled.metadata()) {
+                            panic!("{}", e);
+                        }
+                    }
+
+                    /// Checks that a plugin implements this API with all its functions, without compiling it
+                    pub fn check_compatible(metadata: &PluginMetadata) -> Result<(), ::wasm_plugin_framework::Incompatibility> {
+                        let functions: Vec<&str> = super::metadata::EXPORTS.iter().map(|(name, _)| *name).collect();
+                        metadata.check_api(super::metadata::API_NAME, super::metadata::API_VERSION, &functions)
+                    }
+
+                    /// The compiled module this plugin was instantiated from
+                    pub fn compiled(&self) -> &CompiledPlugin {
+                        &self.compiled
+                    }
+
+                    /// A snapshot of the statistics of the calls made to the plugin, through this loader and the others created from the same compiled plugin
+                    pub fn stats(&self) -> ::wasm_plugin_framework::PluginStats {
+                        self.compiled.stats()
+                    }
+
+                    /// The calls and instructions run by each wasm function of this instance, if the plugin was compiled by an engine with profiling
+                    pub fn profile(&self) -> Option<::wasm_plugin_framework::Profile> {
+                        self.compiled.profile(&self.instance)
+                    }
+
+                    /// The plugin version, authors, description, etc.
+                    pub fn plugin_info(&self) -> &PluginInfo {
+                        self.compiled.info()
+                    }
+
+                    /// Reads the plugin version, authors, description, etc. from the plugin bytes, without compiling nor instantiating it
+                    pub fn read_plugin_info(bytes: &[u8]) -> Result<PluginInfo, MetadataError> {
+                        PluginMetadata::from_bytes(bytes).map(|m| m.info)
+                    }
+
                     #(
                         #methods
                     )*
+
+                    #(
+                        #async_methods
+                    )*
+                }
+
+                #loader_host_impl
+
+                impl ::wasm_plugin_framework::ApiLoader for #loader_name {
+                    const API_NAME: &'static str = super::metadata::API_NAME;
+                    const API_VERSION: &'static str = super::metadata::API_VERSION;
+
+                    fn from_instance(compiled: &CompiledPlugin, instance: &PluginInstance) -> Self {
+                        Self::from_instance(compiled, instance)
+                    }
                 }
 
                 impl::wasm_plugin_framework::abi::PluginLoader for #loader_name {
@@ -218,8 +862,92 @@ impl ToTokens for CommonPluginImplementation {
                 }
             }
 
+            #[cfg(not(target_arch = "wasm32"))]
+            mod pool {
+                use ::wasm_plugin_framework::CompiledPlugin;
+                use super::*;
+
+                /// A pool of instances of the same plugin, which can be called from many threads at once.
+                /// Each call blocks until an instance is available, use `pool().try_get()` to fail instead.
+                /// Cloning it is cheap, as the clones share the same instances.
+                #[derive(Clone)]
+                pub struct #pool_name {
+                    pool: ::std::sync::Arc<::wasm_plugin_framework::PluginPool<#loader_name>>,
+                }
+
+                impl #pool_name {
+                    /// Instantiates the compiled plugin `size` times. Panics if `size` is 0
+                    pub fn new(compiled: &CompiledPlugin, size: usize) -> Self {
+                        Self {
+                            pool: ::std::sync::Arc::new(::wasm_plugin_framework::PluginPool::new((0..size).map(|_| #loader_name::from_compiled(compiled)).collect())),
+                        }
+                    }
+
+                    pub fn pool(&self) -> &::wasm_plugin_framework::PluginPool<#loader_name> {
+                        &self.pool
+                    }
+
+                    #(
+                        #pool_methods
+                    )*
+
+                    #(
+                        #pool_async_methods
+                    )*
+                }
+
+                #pool_host_impl
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            mod shared {
+                use super::*;
+
+                /// A single plugin instance which can be shared between threads. Each call locks the instance.
+                #[derive(Clone)]
+                pub struct #shared_name {
+                    shared: ::wasm_plugin_framework::SharedPlugin<#loader_name>,
+                }
+
+                impl #shared_name {
+                    pub fn new(plugin: #loader_name) -> Self {
+                        Self {
+                            shared: ::wasm_plugin_framework::SharedPlugin::new(plugin),
+                        }
+                    }
+
+                    pub fn shared(&self) -> &::wasm_plugin_framework::SharedPlugin<#loader_name> {
+                        &self.shared
+                    }
+
+                    #(
+                        #shared_methods
+                    )*
+
+                    #(
+                        #shared_async_methods
+                    )*
+                }
+
+                #shared_host_impl
+            }
+
             #[cfg(not(target_arch = "wasm32"))]
             pub use loader::#loader_name;
+            #[cfg(not(target_arch = "wasm32"))]
+            pub use pool::#pool_name;
+            #[cfg(not(target_arch = "wasm32"))]
+            pub use shared::#shared_name;
+
+            // The thread safety guarantees of the generated types
+            #[cfg(not(target_arch = "wasm32"))]
+            const _: fn() = || {
+                fn assert_send<T: Send>() {}
+                fn assert_send_sync<T: Send + Sync>() {}
+                assert_send::<#loader_name>();
+                assert_send_sync::<#pool_name>();
+                assert_send_sync::<#shared_name>();
+            };
 
         };
         r.to_tokens(tokens);
@@ -1,10 +1,12 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input};
+use syn::{parse_macro_input, AttributeArgs, ItemImpl, ItemTrait};
 
 
 
 mod common_impl;
+mod metadata;
+mod validate;
 
 
 
@@ -13,11 +15,14 @@ mod common_impl;
 #[proc_macro]
 /// The common plugin implementation, to be placed in the common library, used by both host and plugin
 /// It consists for now of a name, a version and the plugin struct identifier.
-/// ```
+/// ```ignore
 /// common_plugin_implementation!("API NAME", "0.1.0", Plugin)
 /// ```
-/// const, async and generics will be ignored
-/// functions cannot have a self parameter, nor accept references
+/// It also generates the host-facing `<Loader>Host` trait, implemented by the loader, so that native implementations can be used instead of plugins,
+/// a native loader (`<Loader>Native<T>`) to test the plugins natively, and a mock (`<Loader>Mock`) to test the host code without plugins.
+/// Functions cannot be const, async or generic, have a self parameter, nor accept references
+/// Neither can their names collide with the methods generated for the other functions (`on_<fn>`, `<fn>_calls`, ...)
+/// A crate can only contain one `common_plugin_implementation!`, as its plugin exports are generated by a macro named after `Plugin` at the crate root
 pub fn common_plugin_implementation(tokens: TokenStream) -> TokenStream {
     let input = parse_macro_input!(tokens as common_impl::CommonPluginImplementation);
        
@@ -28,16 +33,76 @@ pub fn common_plugin_implementation(tokens: TokenStream) -> TokenStream {
 }
 
 
+#[proc_macro_attribute]
+/// The attribute form of common_plugin_implementation, placed on the trait the plugins implement.
+/// The version defaults to the version of the crate, the loader to `<Trait>Loader`,
+/// and the host-facing trait implemented by the loader (`host = "..."`) to `<Trait>Host`.
+/// The native loader and the mock are named after the host trait (`<Trait>Native<T>` and `<Trait>Mock`).
+/// ```ignore
+/// #[plugin_api(name = "API NAME", version = "0.1.0", loader = "Plugin")]
+/// pub trait Api {
+///     fn a(arg: A) -> B;
+/// }
+/// ```
+/// Doc comments and `#[deprecated]` on the functions are kept on the loader methods
+/// The plugin exports are generated by a macro named after the trait at the crate root, so the API traits of a crate need distinct names,
+/// even in different modules
+pub fn plugin_api(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let item = parse_macro_input!(item as ItemTrait);
+    match common_impl::CommonPluginImplementation::from_trait(args, item) {
+        Ok(input) => TokenStream::from(quote! {
+            #input
+        }),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
 mod plugin_impl;
 
 
 #[proc_macro]
 /// The plugin implementation.
-/// It takes the common library in which the common_plugin_implementation was used, the plugin name, and the consts and functions required by the plugin
+/// It takes the common library in which the common_plugin_implementation was used, the plugin name, and the consts and functions required by the plugin.
+/// A plugin can implement many APIs, by using it once per common library.
+/// The plugin metadata and dependencies (see plugin_impl) can be given after the name, and the metadata defaults to the one of the crate:
+/// ```ignore
+/// plugin!(common, "PLUGIN 1", version = "1.0.0", authors = "Jane Doe, John Doe", description = "...", homepage = "...", license = "MIT",
+///     fn a(arg: A) -> B { ... }
+/// );
+/// ```
 pub fn plugin(tokens: TokenStream) -> TokenStream {
     let input = parse_macro_input!(tokens as plugin_impl::PluginImplementation);
     let r = quote!{
         #input
     };
     TokenStream::from(r)
-}
\ No newline at end of file
+}
+
+#[proc_macro_attribute]
+/// The attribute form of plugin, placed on the implementation of the API trait for any type.
+/// It takes the common library in which the API was declared, and the plugin name, which defaults to the name of the crate.
+/// The other metadata (`version`, `authors`, `description`, `homepage` and `license`) also defaults to the one of the crate.
+/// When a plugin implements many APIs, it only needs to be given on one of the impl blocks: the values given to the macros win over the defaults,
+/// and loading fails if two impl blocks are given different ones.
+/// On native targets the exports aren't generated, so the plugin can be tested with the native loader of the API (`ApiNative::<MyPlugin>::new()`).
+/// Dependencies on other plugins or APIs are declared with `requires(plugin = "codec", version = "^1.2")`
+/// or `requires(api = "CODEC API", version = "0.1")`, the plugin manager loads them first.
+/// ```ignore
+/// #[plugin_impl(common, name = "PLUGIN 1")]
+/// impl Api for MyPlugin {
+///     fn a(arg: A) -> B { ... }
+/// }
+/// ```
+/// A plugin can implement many APIs, with one impl block each. When an API is declared in a module of the common library,
+/// the path of that module is given instead (`#[plugin_impl(common::describe)]`)
+pub fn plugin_impl(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let item = parse_macro_input!(item as ItemImpl);
+    match plugin_impl::PluginImplementation::from_impl(args, item) {
+        Ok(input) => TokenStream::from(quote! {
+            #input
+        }),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
@@ -0,0 +1,78 @@
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{Ident, LitByteStr, LitStr};
+
+/// The custom section the plugins embed their metadata in, read by `wasm_plugin_framework::PluginMetadata`
+const SECTION: &str = "wasm_plugin_framework";
+
+/// The version of the plugin ABI, which must match `wasm_plugin_framework::abi::ABI_VERSION`
+const ABI_VERSION: u32 = 1;
+
+/// Encodes a metadata record, as the u32 (little endian) length of its fields, followed by the fields.
+/// Each field is a key and a value, each of them being a u32 length followed by UTF-8 bytes.
+/// The linker concatenates the records of all the APIs a plugin implements in the same section.
+fn record(kind: &str, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut push = |s: &str| {
+        body.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        body.extend_from_slice(s.as_bytes());
+    };
+    push("kind");
+    push(kind);
+    push("abi_version");
+    push(&ABI_VERSION.to_string());
+    for (key, value) in fields {
+        push(key);
+        push(value);
+    }
+    let mut record = (body.len() as u32).to_le_bytes().to_vec();
+    record.extend(body);
+    record
+}
+
+/// A static holding the record in the metadata section. The section is only emitted when building for wasm
+fn section_static(ident: Ident, record: Vec<u8>) -> TokenStream {
+    let len = record.len();
+    let bytes = LitByteStr::new(&record, Span::call_site());
+    quote! {
+        #[cfg_attr(target_arch = "wasm32", link_section = #SECTION)]
+        #[used]
+        #[allow(non_upper_case_globals)]
+        static #ident: [u8; #len] = *#bytes;
+    }
+}
+
+/// The record of an API implemented by the plugin
+pub fn api_record(ident: Ident, api_name: &LitStr, api_version: &LitStr, fns: &[String]) -> TokenStream {
+    section_static(
+        ident,
+        record(
+            "api",
+            &[
+                ("name", api_name.value()),
+                ("version", api_version.value()),
+                ("functions", fns.join(",")),
+            ],
+        ),
+    )
+}
+
+/// The record of the plugin itself, with its name, version, authors (one per line), description, homepage and license
+pub fn plugin_record(ident: Ident, fields: &[(&str, String)]) -> TokenStream {
+    section_static(ident, record("plugin", fields))
+}
+
+/// The record of a dependency of the plugin, on another plugin (`on` is "plugin") or on any plugin implementing an API ("api")
+pub fn dependency_record(ident: Ident, on: &str, name: &LitStr, version_req: Option<&LitStr>) -> TokenStream {
+    section_static(
+        ident,
+        record(
+            "dependency",
+            &[
+                ("on", on.to_string()),
+                ("name", name.value()),
+                ("version", version_req.map_or_else(|| "*".to_string(), LitStr::value)),
+            ],
+        ),
+    )
+}
@@ -1,108 +1,314 @@
 use proc_macro2::TokenStream;
-use quote::{quote, ToTokens};
-use syn::{FnArg, ItemFn, LitStr, Path, ReturnType, Token, parse::Parse};
+use quote::{format_ident, quote, ToTokens};
+use syn::{AttributeArgs, Ident, ImplItem, ItemFn, ItemImpl, Lit, LitStr, Meta, MetaList, MetaNameValue, NestedMeta, Path, Token, parse::Parse, token};
+
+use crate::{common_impl::{exports_macro_name, expect_comma}, metadata, validate};
 
 pub struct PluginImplementation {
 	common_lib: Path,
-	plugin_name: LitStr,
-	fns: Vec<ItemFn>
+	/// The name of the crate if it isn't given
+	plugin_name: Option<LitStr>,
+	info: PluginInfoArgs,
+	dependencies: Vec<DependencyArg>,
+	body: PluginBody,
+}
+
+/// A dependency of the plugin: `requires(plugin = "codec", version = "^1.2")` or `requires(api = "CODEC API", version = "0.1")`
+struct DependencyArg {
+	/// Either "plugin" or "api"
+	on: &'static str,
+	name: LitStr,
+	version_req: Option<LitStr>,
+}
+
+const DEPENDENCY_USAGE: &str = "Expected a dependency like `requires(plugin = \"NAME\", version = \"^1.0\")` or `requires(api = \"API NAME\", version = \"^1.0\")`";
+
+impl DependencyArg {
+	/// Parses the arguments of `requires(...)`
+	fn from_list(list: &MetaList) -> syn::Result<Self> {
+		let mut target = None;
+		let mut version_req = None;
+		for arg in &list.nested {
+			let nv = match arg {
+				NestedMeta::Meta(Meta::NameValue(nv)) => nv,
+				arg => return Err(syn::Error::new_spanned(arg, DEPENDENCY_USAGE)),
+			};
+			let value = match &nv.lit {
+				Lit::Str(s) => s.clone(),
+				lit => return Err(syn::Error::new_spanned(lit, "Expected a string literal")),
+			};
+			if nv.path.is_ident("plugin") || nv.path.is_ident("api") {
+				if target.is_some() {
+					return Err(syn::Error::new_spanned(nv, "A dependency is either on a plugin or on an API"));
+				}
+				target = Some((if nv.path.is_ident("plugin") { "plugin" } else { "api" }, value));
+			} else if nv.path.is_ident("version") {
+				validate::validate_version_req(&value)?;
+				version_req = Some(value);
+			} else {
+				return Err(syn::Error::new_spanned(&nv.path, DEPENDENCY_USAGE));
+			}
+		}
+		let (on, name) = target.ok_or_else(|| syn::Error::new_spanned(list, DEPENDENCY_USAGE))?;
+		Ok(Self { on, name, version_req })
+	}
+}
+
+/// The optional plugin metadata, which defaults to the one cargo sets for the crate
+#[derive(Default)]
+struct PluginInfoArgs {
+	version: Option<LitStr>,
+	/// Comma separated
+	authors: Option<LitStr>,
+	description: Option<LitStr>,
+	homepage: Option<LitStr>,
+	license: Option<LitStr>,
+}
+
+const UNKNOWN_INFO_ARG: &str = "Unknown argument, expected one of `version`, `authors`, `description`, `homepage`, `license` or `requires(...)`";
+
+impl PluginInfoArgs {
+	/// Sets the argument if it is one of the plugin metadata, returns false if it isn't
+	fn set(&mut self, nv: &MetaNameValue) -> syn::Result<bool> {
+		let value = match &nv.lit {
+			Lit::Str(s) => s.clone(),
+			lit => return Err(syn::Error::new_spanned(lit, "Expected a string literal")),
+		};
+		let field = if nv.path.is_ident("version") {
+			validate::validate_version(&value)?;
+			&mut self.version
+		} else if nv.path.is_ident("authors") {
+			&mut self.authors
+		} else if nv.path.is_ident("description") {
+			&mut self.description
+		} else if nv.path.is_ident("homepage") {
+			&mut self.homepage
+		} else if nv.path.is_ident("license") {
+			&mut self.license
+		} else {
+			return Ok(false);
+		};
+		*field = Some(value);
+		Ok(true)
+	}
+
+	/// The fields of the metadata record, with the authors on separate lines.
+	/// The `explicit` field lists the ones given to the macro, which take precedence over the defaults of the other API implementations
+	fn record_fields(&self, plugin_name: String, explicit_name: bool) -> Vec<(&'static str, String)> {
+		let value = |arg: &Option<LitStr>, env: &str| match arg {
+			Some(arg) => arg.value(),
+			// Proc macros run with the environment cargo sets for the crate being compiled
+			None => std::env::var(env).unwrap_or_default(),
+		};
+		let authors: Vec<String> = match &self.authors {
+			Some(authors) => authors.value().split(',').map(|a| a.trim().to_string()).collect(),
+			None => value(&None, "CARGO_PKG_AUTHORS").split(':').map(str::to_string).collect(),
+		};
+		vec![
+			("name", plugin_name),
+			("version", value(&self.version, "CARGO_PKG_VERSION")),
+			("authors", authors.join("\n")),
+			("description", value(&self.description, "CARGO_PKG_DESCRIPTION")),
+			("homepage", value(&self.homepage, "CARGO_PKG_HOMEPAGE")),
+			("license", value(&self.license, "CARGO_PKG_LICENSE")),
+			("explicit", self.explicit_fields(explicit_name).join(",")),
+		]
+	}
+
+	fn explicit_fields(&self, explicit_name: bool) -> Vec<&'static str> {
+		let args = [
+			("name", explicit_name),
+			("version", self.version.is_some()),
+			("authors", self.authors.is_some()),
+			("description", self.description.is_some()),
+			("homepage", self.homepage.is_some()),
+			("license", self.license.is_some()),
+		];
+		args.iter().filter(|(_, given)| *given).map(|(key, _)| *key).collect()
+	}
+}
+
+enum PluginBody {
+	/// The functions given to the plugin macro, which are implemented on a hidden struct
+	Fns(Vec<ItemFn>),
+	/// The impl block the plugin_impl attribute was placed on
+	Impl(Box<ItemImpl>),
 }
 
 impl Parse for PluginImplementation {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        let common_lib = input.parse()?;
-		let _: Token![,] = input.parse()?;
-        let plugin_name = input.parse()?;
-		let _: Token![,] = input.parse()?;
-		let mut fns = Vec::new();
+        let common_lib = input.parse().map_err(|e| syn::Error::new(e.span(), "Expected the path of the common library"))?;
+		expect_comma(input, "the common library")?;
+        let plugin_name = input.parse().map_err(|e| syn::Error::new(e.span(), "Expected the plugin name, as a string literal"))?;
+		expect_comma(input, "the plugin name")?;
+		let mut info = PluginInfoArgs::default();
+		let mut dependencies = Vec::new();
+		while input.peek(Ident) && (input.peek2(Token![=]) || input.peek2(token::Paren)) {
+			match input.parse()? {
+				Meta::NameValue(nv) => {
+					if !info.set(&nv)? {
+						return Err(syn::Error::new_spanned(nv.path, UNKNOWN_INFO_ARG));
+					}
+				}
+				Meta::List(list) if list.path.is_ident("requires") => dependencies.push(DependencyArg::from_list(&list)?),
+				meta => return Err(syn::Error::new_spanned(meta.path(), UNKNOWN_INFO_ARG)),
+			}
+			expect_comma(input, "the argument")?;
+		}
+		let mut fns: Vec<ItemFn> = Vec::new();
 		while !input.is_empty() {
 			fns.push(input.parse()?)
 		}
+		validate::validate_fns(fns.iter().map(|f| &f.sig))?;
 
 		Ok(Self {
-			common_lib, plugin_name, fns
+			common_lib, plugin_name: Some(plugin_name), info, dependencies, body: PluginBody::Fns(fns)
 		})
     }
 }
 
+impl PluginImplementation {
+	/// Takes the implementation from an impl block of the API trait.
+	/// The first attribute argument is the common library, optionally followed by the plugin name (`name = "PLUGIN NAME"`)
+	/// and metadata (`version`, `authors`, `description`, `homepage` and `license`)
+	pub fn from_impl(args: AttributeArgs, item: ItemImpl) -> syn::Result<Self> {
+		let mut args = args.into_iter();
+		let common_lib = match args.next() {
+			Some(NestedMeta::Meta(Meta::Path(p))) => p,
+			Some(arg) => return Err(syn::Error::new_spanned(arg, "Expected the path of the common library")),
+			None => return Err(syn::Error::new_spanned(&item.self_ty, "Missing the common library: #[plugin_impl(common)]")),
+		};
+		let mut plugin_name = None;
+		let mut info = PluginInfoArgs::default();
+		let mut dependencies = Vec::new();
+		for arg in args {
+			match arg {
+				NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => match nv.lit {
+					Lit::Str(s) => plugin_name = Some(s),
+					lit => return Err(syn::Error::new_spanned(lit, "Expected a string literal")),
+				},
+				NestedMeta::Meta(Meta::NameValue(nv)) => {
+					if !info.set(&nv)? {
+						return Err(syn::Error::new_spanned(nv.path, "Unknown argument, expected one of `name`, `version`, `authors`, `description`, `homepage`, `license` or `requires(...)`"));
+					}
+				}
+				NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("requires") => dependencies.push(DependencyArg::from_list(&list)?),
+				arg => return Err(syn::Error::new_spanned(arg, "Expected an argument like `name = \"PLUGIN NAME\"`")),
+			}
+		}
+		if item.trait_.is_none() {
+			return Err(syn::Error::new_spanned(&item.self_ty, "Expected an implementation of the API trait: impl Api for MyPlugin"));
+		}
+		if !item.generics.params.is_empty() {
+			return Err(syn::Error::new_spanned(&item.generics, "Plugin implementations can't be generic"));
+		}
+		let mut errors: Option<syn::Error> = None;
+		for i in &item.items {
+			if let ImplItem::Method(_) = i {
+				continue;
+			}
+			let e = syn::Error::new_spanned(i, "Plugin implementations can only contain functions");
+			match &mut errors {
+				Some(errors) => errors.combine(e),
+				None => errors = Some(e),
+			}
+		}
+		if let Some(e) = errors {
+			return Err(e);
+		}
+		validate::validate_fns(item.items.iter().filter_map(|i| match i {
+			ImplItem::Method(m) => Some(&m.sig),
+			_ => None
+		}))?;
+		Ok(Self {
+			common_lib, plugin_name, info, dependencies, body: PluginBody::Impl(Box::new(item))
+		})
+	}
+}
+
 impl ToTokens for PluginImplementation {
     fn to_tokens(&self, tokens: &mut TokenStream) {
 		let common_lib = &self.common_lib;
-		let mut plugin_name_s = self.plugin_name.value();
-		plugin_name_s.push('\0');
-		let plugin_name = LitStr::new(&plugin_name_s, self.plugin_name.span());
-		let fns = &self.fns;
-		let abi_fns: Vec<_> = self.fns.iter().map(|a| {
-			let fn_token = &a.sig.fn_token;
-			let ident = &a.sig.ident;
-			let abi_args_compound: Vec<_> = a.sig.inputs.clone().into_iter().enumerate().map(|(i, x)| match x {
-				FnArg::Receiver(_) => unreachable!(),
-				FnArg::Typed(_) => {
-					let argname = quote::format_ident!("arg{}", i);
-					(quote!(#argname: u32), quote! {#common_lib::wasm_plugin_framework::abi::from_abi(#argname)})
-				}
-			}).collect();
-			let mut abi_args = Vec::with_capacity(abi_args_compound.len());
-			let mut abi_args_conversions = Vec::with_capacity(abi_args_compound.len());
-
-			for (a, b) in abi_args_compound {
-				abi_args.push(a);
-				abi_args_conversions.push(b);
+		let plugin_name = match &self.plugin_name {
+			Some(plugin_name) => {
+				let mut plugin_name_s = plugin_name.value();
+				plugin_name_s.push('\0');
+				let plugin_name = LitStr::new(&plugin_name_s, plugin_name.span());
+				quote!(#plugin_name)
 			}
-
-			let calling_code = quote! {
-				Plugin::#ident(#(#abi_args_conversions),*)
-			};
-
-			let (body, return_t) = match &a.sig.output {
-				ReturnType::Default => (quote! {
-					#calling_code;
-				}, quote!()),
-				ReturnType::Type(_, _) => (quote! {
-					#common_lib::wasm_plugin_framework::abi::into_abi(&#calling_code)
-				}, quote!(-> u32))
-			};
-
-			quote!{
-				#[no_mangle]
-				pub extern "C" #fn_token #ident(#(#abi_args),*) #return_t {#body}
+			None => quote!(concat!(env!("CARGO_PKG_NAME"), "\0")),
+		};
+		let plugin_name_value = match &self.plugin_name {
+			Some(plugin_name) => plugin_name.value(),
+			None => std::env::var("CARGO_PKG_NAME").unwrap_or_default(),
+		};
+		// Each API implementation embeds it, the host merges them
+		let plugin_record = metadata::plugin_record(
+			format_ident!("__WPF_PLUGIN_METADATA"),
+			&self.info.record_fields(plugin_name_value, self.plugin_name.is_some()),
+		);
+		let dependency_records = self.dependencies.iter().enumerate().map(|(i, d)| {
+			metadata::dependency_record(format_ident!("__WPF_DEPENDENCY_{}", i), d.on, &d.name, d.version_req.as_ref())
+		});
+		let plugin_record = quote!(#plugin_record #(#dependency_records)*);
+		// The common library generates the exports, as only it knows the symbols of the API functions.
+		// Its macros are exported at the root of the crate, while the API may be declared in a module of it
+		let common_crate = &self.common_lib.segments.first().unwrap().ident;
+		let leading_colon = &self.common_lib.leading_colon;
+		let exports = match &self.body {
+			PluginBody::Fns(_) => {
+				let exports_macro = exports_macro_name(&format_ident!("Plugin"));
+				quote!(#leading_colon #common_crate::#exports_macro!(Plugin, PluginTrait, #plugin_name);)
 			}
-		}).collect();
-        let r = quote!{
-			/// The plugin metadata functions (eg. name)
-			pub mod metadata {
-				#[no_mangle]
-				pub extern "C" fn API_NAME() -> *const u8 {
-					#common_lib::metadata::API_NAME_C.as_ptr()
-				}
+			PluginBody::Impl(item) => {
+				let self_ty = &item.self_ty;
+				let trait_path = &item.trait_.as_ref().unwrap().1;
+				let trait_ident = &trait_path.segments.last().unwrap().ident;
+				let exports_macro = exports_macro_name(trait_ident);
+				quote!(#leading_colon #common_crate::#exports_macro!(#self_ty, #trait_path, #plugin_name);)
+			}
+		};
+		// A module per API, so that a plugin can implement many of them
+		let module_name = self.common_lib.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<_>>().join("_");
+		let r = match &self.body {
+			PluginBody::Fns(fns) => {
+				let module = format_ident!("__plugin_impl_{}", module_name);
+				quote!{
+					#[doc(hidden)]
+					mod #module {
+						use super::*;
 
-				#[no_mangle]
-				pub extern "C" fn API_VERSION() -> *const u8 {
-					#common_lib::metadata::API_VERSION_C.as_ptr()
-				}
+						use #common_lib::metadata::Plugin as PluginTrait;
+						/// The plugin implementation, which can be called natively (eg. in tests) through the native loader of the API
+						pub struct Plugin;
+
+						impl PluginTrait for Plugin {
+							#(#fns)*
+						}
 
-				#[no_mangle]
-				pub extern "C" fn PLUGIN_NAME() -> *const u8 {
-					#plugin_name.as_ptr()
+						#exports
+						#plugin_record
+					}
 				}
 			}
-			// So that the names dont collide, as the modules dont matter when exporting
-			use metadata::*;
+			PluginBody::Impl(item) => {
+				let trait_ident = &item.trait_.as_ref().unwrap().1.segments.last().unwrap().ident;
+				let module = format_ident!("__plugin_exports_{}_{}", module_name, trait_ident);
+				quote!{
+					#item
 
-			mod plugin_impl {
-				use super::*;
+					/// The exported functions of the plugin, the modules dont matter when exporting
+					#[doc(hidden)]
+					#[allow(non_snake_case)]
+					mod #module {
+						use super::*;
 
-				use #common_lib::metadata::Plugin as PluginTrait;
-				struct Plugin;
-
-				impl PluginTrait for Plugin {
-					#(#fns)*
+						#exports
+						#plugin_record
+					}
 				}
-
-				#(#abi_fns)*
 			}
-			use plugin_impl::*;
 		};
 		r.to_tokens(tokens);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use syn::{
+    FnArg, GenericArgument, LitStr, Pat, PathArguments, ReturnType, Signature, Type,
+};
+
+/// Names which can't be used for API functions, as they collide with the functions exported by the framework,
+/// or with the methods of the generated loader and mock
+const RESERVED_NAMES: &[&str] = &[
+    "API_NAME",
+    "API_VERSION",
+    "PLUGIN_NAME",
+    "allocate_buffer",
+    "free_buffer",
+    "memory",
+    "new",
+    "new_with_engine",
+    "compile",
+    "from_compiled",
+    "from_package",
+    "from_instance",
+    "with_recorder",
+    "stats",
+    "profile",
+    "compiled",
+    "check_api",
+    "check_compatible",
+    "plugin_info",
+    "read_plugin_info",
+    "calls",
+    "assert_not_called",
+];
+
+/// Adds the error to the errors found so far
+fn push(errors: &mut Option<syn::Error>, error: syn::Error) {
+    match errors {
+        Some(errors) => errors.combine(error),
+        None => *errors = Some(error),
+    }
+}
+
+/// Checks that the API functions can go through the plugin ABI, with errors pointing at the offending tokens
+pub fn validate_fns<'a, I>(sigs: I) -> syn::Result<()>
+where
+    I: IntoIterator<Item = &'a Signature>,
+{
+    let mut errors = None;
+    let mut names = HashMap::new();
+    for sig in sigs {
+        if let Err(e) = validate_signature(sig) {
+            push(&mut errors, e);
+        }
+        let name = sig.ident.to_string();
+        if RESERVED_NAMES.contains(&name.as_str()) {
+            push(
+                &mut errors,
+                syn::Error::new_spanned(
+                    &sig.ident,
+                    format!("`{}` is reserved by the plugin framework, use another name", name),
+                ),
+            );
+        }
+        // The function itself first, then the methods generated for it
+        for (i, generated) in generated_names(&name).iter().enumerate() {
+            let is_function = i == 0;
+            let (first, first_is_function) = match names.get(generated) {
+                Some(&first) => first,
+                None => {
+                    names.insert(generated.clone(), (&sig.ident, is_function));
+                    continue;
+                }
+            };
+            let message = match (is_function, first_is_function) {
+                (true, true) => format!("Duplicate API function `{}`", name),
+                (true, false) => format!("`{}` collides with a method generated for `{}`", name, first),
+                (false, true) => format!("The `{}` method generated for `{}` collides with the API function `{}`", generated, name, first),
+                (false, false) => format!("The `{}` method generated for `{}` collides with the one generated for `{}`", generated, name, first),
+            };
+            let mut e = syn::Error::new_spanned(&sig.ident, message);
+            e.combine(syn::Error::new_spanned(first, "first defined here"));
+            push(&mut errors, e);
+            break;
+        }
+    }
+    errors.map_or(Ok(()), Err)
+}
+
+/// The names of the function and of the methods generated for it on the loader and the mock
+fn generated_names(name: &str) -> [String; 6] {
+    [
+        name.to_string(),
+        format!("{}_async", name),
+        format!("on_{}", name),
+        format!("{}_calls", name),
+        format!("{}_call_count", name),
+        format!("assert_{}_called", name),
+    ]
+}
+
+fn validate_signature(sig: &Signature) -> syn::Result<()> {
+    let mut errors = None;
+    if let Some(constness) = &sig.constness {
+        push(&mut errors, syn::Error::new_spanned(constness, "API functions can't be `const`"));
+    }
+    if let Some(asyncness) = &sig.asyncness {
+        push(&mut errors, syn::Error::new_spanned(asyncness, "API functions can't be `async`"));
+    }
+    if let Some(abi) = &sig.abi {
+        push(&mut errors, syn::Error::new_spanned(abi, "API functions can't have an ABI, the plugin framework provides it"));
+    }
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        push(&mut errors, syn::Error::new_spanned(&sig.generics, "API functions can't be generic"));
+    }
+    if let Some(variadic) = &sig.variadic {
+        push(&mut errors, syn::Error::new_spanned(variadic, "API functions can't be variadic"));
+    }
+    for arg in &sig.inputs {
+        match arg {
+            FnArg::Receiver(r) => push(
+                &mut errors,
+                syn::Error::new_spanned(r, "API functions can't take `self`, there is no plugin state shared between calls"),
+            ),
+            FnArg::Typed(p) => {
+                match &*p.pat {
+                    Pat::Ident(i) if i.by_ref.is_none() && i.mutability.is_none() && i.subpat.is_none() => (),
+                    pat => push(
+                        &mut errors,
+                        syn::Error::new_spanned(pat, "API function arguments must be simple identifiers, like `arg: A`"),
+                    ),
+                }
+                if let Err(e) = validate_type(&p.ty) {
+                    push(&mut errors, e);
+                }
+            }
+        }
+    }
+    if let ReturnType::Type(_, ty) = &sig.output {
+        if let Err(e) = validate_type(ty) {
+            push(&mut errors, e);
+        }
+    }
+    errors.map_or(Ok(()), Err)
+}
+
+/// Checks that the type is owned, as it has to be serialized across the ABI
+fn validate_type(ty: &Type) -> syn::Result<()> {
+    match ty {
+        Type::Reference(r) => Err(syn::Error::new_spanned(
+            r,
+            "API functions can't take or return references, as the values are copied between the host and the plugin memory",
+        )),
+        Type::ImplTrait(i) => Err(syn::Error::new_spanned(i, "API functions can't use `impl Trait`, use a concrete type")),
+        Type::Array(a) => validate_type(&a.elem),
+        Type::Slice(s) => validate_type(&s.elem),
+        Type::Paren(p) => validate_type(&p.elem),
+        Type::Group(g) => validate_type(&g.elem),
+        Type::Tuple(t) => t.elems.iter().try_for_each(validate_type),
+        Type::Path(p) => p.path.segments.iter().try_for_each(|s| match &s.arguments {
+            PathArguments::AngleBracketed(a) => a.args.iter().try_for_each(|a| match a {
+                GenericArgument::Type(t) => validate_type(t),
+                _ => Ok(()),
+            }),
+            _ => Ok(()),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that the version is a valid semver version (MAJOR.MINOR.PATCH, with an optional pre-release and build)
+pub fn validate_version(version: &LitStr) -> syn::Result<()> {
+    semver::Version::parse(&version.value()).map(|_| ()).map_err(|e| {
+        syn::Error::new_spanned(
+            version,
+            format!("`{}` is not a valid semver version, expected something like \"0.1.0\": {}", version.value(), e),
+        )
+    })
+}
+
+/// Checks that the version requirement is a valid semver requirement, like the ones of cargo dependencies
+pub fn validate_version_req(req: &LitStr) -> syn::Result<()> {
+    semver::VersionReq::parse(&req.value())
+        .map(|_| ())
+        .map_err(|e| syn::Error::new_spanned(req, format!("`{}` is not a valid version requirement: {}", req.value(), e)))
+}
@@ -0,0 +1,8 @@
+use wasm_plugin_framework_macros::common_plugin_implementation;
+
+common_plugin_implementation!("API NAME", "0.1.0", Plugin,
+    fn a((x, y): (u32, u32)) -> u32;
+    fn b(mut arg: u32) -> u32;
+);
+
+fn main() {}
@@ -0,0 +1,9 @@
+use wasm_plugin_framework_macros::plugin_api;
+
+#[plugin_api(name = "API NAME", version = "0.1.0")]
+pub trait Api {
+    fn a(arg: u32) -> u32;
+    fn a(arg: u64) -> u64;
+}
+
+fn main() {}
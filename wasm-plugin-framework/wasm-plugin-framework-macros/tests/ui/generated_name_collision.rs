@@ -0,0 +1,12 @@
+use wasm_plugin_framework_macros::plugin_api;
+
+#[plugin_api(name = "API NAME", version = "0.1.0")]
+pub trait Api {
+    fn a(arg: u32) -> u32;
+    fn a_calls(arg: u32) -> u32;
+    fn on_b(arg: u32) -> u32;
+    fn b_calls(arg: u32) -> u32;
+    fn b(arg: u32) -> u32;
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+use wasm_plugin_framework_macros::common_plugin_implementation;
+
+common_plugin_implementation!("API NAME", "0.1.0", Plugin,
+    fn a<T>(arg: T) -> u32;
+);
+
+fn main() {}
@@ -0,0 +1,12 @@
+use wasm_plugin_framework_macros::plugin_impl;
+
+struct Plugin;
+
+#[plugin_impl(common, requires(plugin = "codec", version = "not a version"))]
+impl Api for Plugin {
+    fn a(arg: u32) -> u32 {
+        arg
+    }
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+use wasm_plugin_framework_macros::common_plugin_implementation;
+
+common_plugin_implementation!(API_NAME, "0.1.0", Plugin,
+    fn a(arg: u32) -> u32;
+);
+
+fn main() {}
@@ -0,0 +1,8 @@
+use wasm_plugin_framework_macros::plugin_api;
+
+#[plugin_api(name = "API NAME", version = 1)]
+pub trait Api {
+    fn a(arg: u32) -> u32;
+}
+
+fn main() {}
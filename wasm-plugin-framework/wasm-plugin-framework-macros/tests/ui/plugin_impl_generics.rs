@@ -0,0 +1,16 @@
+use wasm_plugin_framework_macros::plugin_impl;
+
+pub trait Api {
+    fn a(arg: u32) -> u32;
+}
+
+struct MyPlugin;
+
+#[plugin_impl(common)]
+impl Api for MyPlugin {
+    fn a<T>(arg: u32) -> u32 {
+        arg
+    }
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use wasm_plugin_framework_macros::plugin;
+
+plugin!(common, "PLUGIN 1",
+    fn a(&self, arg: u32) -> u32 {
+        arg
+    }
+);
+
+fn main() {}
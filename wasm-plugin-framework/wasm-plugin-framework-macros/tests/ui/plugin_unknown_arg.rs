@@ -0,0 +1,9 @@
+use wasm_plugin_framework_macros::plugin;
+
+plugin!(common, "PLUGIN 1", version = "1.0.0", website = "https://example.com",
+    fn a(arg: u32) -> u32 {
+        arg
+    }
+);
+
+fn main() {}
@@ -0,0 +1,10 @@
+use wasm_plugin_framework_macros::common_plugin_implementation;
+
+common_plugin_implementation!("API NAME", "0.1.0", Plugin,
+    fn API_NAME() -> u32;
+    fn allocate_buffer(size: u32) -> u32;
+    fn memory();
+    fn new(arg: u32);
+);
+
+fn main() {}
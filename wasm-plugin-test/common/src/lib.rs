@@ -1,9 +1,11 @@
 pub use wasm_plugin_framework;
-use wasm_plugin_framework::common_plugin_implementation;
+use wasm_plugin_framework::plugin_api;
 
-common_plugin_implementation!("API NAME", "0.1.0", Plugin,
+#[plugin_api(name = "API NAME", version = "0.1.0", loader = "Plugin")]
+pub trait Api {
+    /// Turns an A into a B
     fn a(arg: A) -> B;
-);
+}
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct A {
@@ -18,3 +20,16 @@ pub struct B {
     pub test2: String,
 }
 
+
+/// A second API, which plugins can implement besides the first one
+pub mod describe {
+    use wasm_plugin_framework::plugin_api;
+
+    use super::A;
+
+    #[plugin_api(name = "DESCRIBE", version = "0.1.0")]
+    pub trait Describe {
+        /// Describes an A
+        fn describe(arg: A) -> String;
+    }
+}
@@ -1,27 +1,79 @@
-use wasmer::Exports;
-
-
 fn main() -> anyhow::Result<()> {
     let bytes = include_bytes!("wasm.wasm");
 
+    // The metadata can be read without compiling the plugin
+    let metadata = common::wasm_plugin_framework::PluginMetadata::from_bytes(bytes)?;
+    println!("METADATA: {:?}", metadata);
+
     let p = common::Plugin::new(bytes);
     println!("API NAME: {}", common::metadata::API_NAME);
     println!("API VERSION: {}", common::metadata::API_VERSION);
     println!("PLUGIN NAME: {}", p.name);
+    println!("PLUGIN INFO: {:?}", p.plugin_info());
+    println!("PROVIDED APIS: {:?}", p.compiled().apis());
 
-    let e: &Exports = &(&p).instance.exports;
-
-    for (name, extrn) in e.iter() {
-        println!("{}: {:?}", name, extrn);
-    }
-
-    println!("{:?}", e.get_function("main").unwrap());
+    // The exports, imports and memories of the plugin are printed by `wasm-plugin inspect wasm.wasm`
 
     let a = common::A { test: "Hey Ho".into(), test2: 1000000000000000000u64 };
-    let b = p.a(a);
+    let describe = common::describe::DescribeLoader::from_instance(p.compiled(), &p.instance);
+    println!("{}", describe.describe(common::A { test: a.test.clone(), test2: a.test2 }));
+
+    let b = call_api(&p, a);
     println!("{:?}", b);
 
+    for f in p.stats().functions {
+        println!(
+            "{}: {} calls, guest p50 {:?}, serialize p50 {:?}, deserialize p50 {:?}",
+            f.function,
+            f.calls,
+            f.guest.percentile(0.5).unwrap_or_default(),
+            f.serialize.percentile(0.5).unwrap_or_default(),
+            f.deserialize.percentile(0.5).unwrap_or_default(),
+        );
+    }
+
     
     
     Ok(())
 }
+
+/// Works with any implementation of the API, be it a wasm plugin or a native one
+fn call_api(api: &dyn common::ApiHost, a: common::A) -> common::B {
+    api.a(a)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{ApiMock, A, B};
+
+    #[test]
+    fn call_api_calls_the_plugin() {
+        let mock = ApiMock::new().on_a(|a| B { test: a.test, test2: a.test2.to_string() });
+        let b = super::call_api(&mock, A { test: "Hey Ho".into(), test2: 1 });
+        assert_eq!(b.test2, "1");
+        mock.assert_a_called(1);
+        assert_eq!(mock.a_calls()[0].0.test, "Hey Ho");
+    }
+
+    #[test]
+    fn mock_calls_run_concurrently() {
+        use std::sync::{Arc, Barrier};
+
+        // Each call waits for the other one, which would deadlock if the mock held a lock while calling the response
+        let barrier = Barrier::new(2);
+        let mock = Arc::new(ApiMock::new().on_a(move |a| {
+            barrier.wait();
+            B { test: a.test, test2: String::new() }
+        }));
+        let threads: Vec<_> = (0..2)
+            .map(|i| {
+                let mock = mock.clone();
+                std::thread::spawn(move || mock.a(A { test: i.to_string(), test2: i }))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        mock.assert_a_called(2);
+    }
+}
@@ -1,6 +1,11 @@
-use common::wasm_plugin_framework::{plugin, abi};
+use common::wasm_plugin_framework::plugin_impl;
+use common::describe::Describe;
+use common::{Api, A, B};
 
-plugin!(common, "PLUGIN 1",
+struct Plugin1;
+
+#[plugin_impl(common, name = "PLUGIN 1", description = "An example plugin")]
+impl Api for Plugin1 {
     fn a(a: A) -> B {
         println!("{:?}", a);
         let b = B {
@@ -9,6 +14,34 @@ plugin!(common, "PLUGIN 1",
         };
         b
     }
-);
+}
+
+#[plugin_impl(common::describe, name = "PLUGIN 1")]
+impl Describe for Plugin1 {
+    fn describe(a: A) -> String {
+        format!("{} ({})", a.test, a.test2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::describe::{DescribeHost, DescribeNative};
+    use common::{ApiHost, ApiNative, A};
+
+    use super::Plugin1;
+
+    fn a() -> A {
+        A { test: "Hey Ho".into(), test2: 42 }
+    }
 
-use common::{A, B};
+    #[test]
+    fn api() {
+        let b = ApiNative::<Plugin1>::new().a(a());
+        assert_eq!(b.test, "AAAAAAAAAAAAAAAAAAAAA");
+    }
+
+    #[test]
+    fn describe() {
+        assert_eq!(DescribeNative::<Plugin1>::new().describe(a()), "Hey Ho (42)");
+    }
+}